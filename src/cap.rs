@@ -0,0 +1,195 @@
+use crate::AtomicWriteFile;
+use crate::OpenOptions;
+use crate::Stage;
+use crate::error;
+use crate::error::ResultExt;
+use crate::imp;
+use cap_std::fs::Dir;
+use cap_std::fs::DirBuilder;
+use cap_std::fs::DirBuilderExt;
+use nix::sys::stat::mode_t;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+impl OpenOptions {
+    /// Opens the file at `path`, relative to the directory `dir`, with this set of options.
+    ///
+    /// This is similar to [`open()`](OpenOptions::open), except that `path` is resolved by
+    /// [`cap_std`], and is confined beneath `dir`: `path` cannot be absolute, and it cannot refer
+    /// to a location outside of `dir`, neither through `..` components nor through symlinks.
+    /// Ambient paths (like the current working directory) are never used. If
+    /// [`temporary_dir()`](OpenOptions::temporary_dir) is set, it is resolved in the same way,
+    /// relative to the parent directory of `path`.
+    ///
+    /// All the other operations (creating the temporary file, copying metadata, and renaming the
+    /// temporary file at commit time) are performed relative to the resolved directory, and do not
+    /// involve any further path resolution. If [`metadata_from()`](OpenOptions::metadata_from) is
+    /// set, the template file is also resolved by [`cap_std`], relative to `dir` (not to the parent
+    /// directory of `path`), and it must be beneath `dir` too.
+    ///
+    /// The options that involve other paths are honored beneath `dir` too, except where noted:
+    ///
+    /// * With [`create_parents()`](OpenOptions::create_parents), the missing directories are
+    ///   created beneath `dir`, and synced when the file is committed.
+    ///
+    /// * With [`check_directory()`](OpenOptions::check_directory), the parent directory of `path`
+    ///   is resolved again beneath `dir` when the file is committed.
+    ///
+    /// * With [`in_place_fallback()`](OpenOptions::in_place_fallback), the contents are written in
+    ///   place if the destination cannot be replaced, as usual. However, the system temporary
+    ///   directory is outside of `dir`, so it cannot be used if the temporary file cannot be
+    ///   created: in that case, an error of kind [`Unsupported`](ErrorKind::Unsupported) is
+    ///   returned.
+    ///
+    /// This method is only available on Unix, with the `cap-std` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use cap_std::ambient_authority;
+    /// use cap_std::fs::Dir;
+    /// use std::io::Write;
+    ///
+    /// let dir = Dir::open_ambient_dir(".", ambient_authority())?;
+    /// let mut file = OpenOptions::new().open_at(&dir, "foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// file.commit()?;
+    ///
+    /// // Paths outside of `dir` are rejected
+    /// assert!(OpenOptions::new().open_at(&dir, "../foo.txt").is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_at<P: AsRef<Path>>(&self, dir: &Dir, path: P) -> Result<AtomicWriteFile> {
+        let path = path.as_ref();
+        self.inner.naming.validate()?;
+        let base_dir = Arc::new(dir.try_clone()?);
+        let options = self.confined_to(&base_dir);
+
+        let name = path.file_name().ok_or(ErrorKind::IsADirectory)?;
+        let (temporary_file, in_place) = options
+            .open_with_fallback(true, |opts| open_at_parent(opts, dir, path, name))
+            .map_err(|err| err.with_path(path))?;
+        let mut file = AtomicWriteFile::new(&options, temporary_file, path.to_path_buf());
+        file.in_place = in_place;
+        if file.check_directory {
+            file.base_dir = Some(base_dir);
+        }
+        Ok(file)
+    }
+
+    /// Returns these options, with the template file (if any) resolved beneath `base_dir`.
+    fn confined_to(&self, base_dir: &Arc<Dir>) -> Cow<'_, Self> {
+        match self.inner.metadata_from {
+            Some(imp::Template::Path(ref template)) => {
+                let mut options = self.clone();
+                options.inner.metadata_from =
+                    Some(imp::Template::At(Arc::clone(base_dir), template.clone()));
+                Cow::Owned(options)
+            }
+            _ => Cow::Borrowed(self),
+        }
+    }
+}
+
+/// Opens a temporary file for `path` beneath `dir`, creating the parent directories of `path` if
+/// requested by `opts`.
+fn open_at_parent(
+    opts: &imp::OpenOptions,
+    dir: &Dir,
+    path: &Path,
+    name: &OsStr,
+) -> error::Result<imp::TemporaryFile> {
+    let (parent, unsynced_parents) = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => match dir.open_dir(parent) {
+            Err(ref err) if opts.create_parents && err.kind() == ErrorKind::NotFound => {
+                let unsynced_parents = create_dir_all(dir, parent, opts.parents_mode)
+                    .at_stage(Stage::CreateDirectory)?;
+                let parent = dir.open_dir(parent).at_stage(Stage::OpenDirectory)?;
+                (parent, unsynced_parents)
+            }
+            result => (result.at_stage(Stage::OpenDirectory)?, Vec::new()),
+        },
+        _ => (dir.try_clone().at_stage(Stage::OpenDirectory)?, Vec::new()),
+    };
+    let temporary_dir = match opts.naming.dir {
+        Some(ref temporary_dir) => Some(
+            open_or_create_dir(&parent, temporary_dir)
+                .and_then(imp::Dir::reopen)
+                .at_stage(Stage::OpenTemporaryDirectory)?,
+        ),
+        None => None,
+    };
+    let parent = imp::Dir::reopen(parent).at_stage(Stage::OpenDirectory)?;
+    let mut temporary_file =
+        imp::TemporaryFile::open_with_dirs(opts, Arc::new(parent), temporary_dir, name)?;
+    temporary_file.unsynced_parents = unsynced_parents;
+    Ok(temporary_file)
+}
+
+/// Creates the directory `path` beneath `dir`, and its missing ancestors, with mode `mode` (masked
+/// by the umask). Returns the parents of the directories that were created.
+fn create_dir_all(dir: &Dir, path: &Path, mode: mode_t) -> Result<Vec<imp::Dir>> {
+    let open_ancestor = |path: &Path| {
+        if !path.as_os_str().is_empty() {
+            dir.open_dir(path)
+        } else {
+            dir.try_clone()
+        }
+    };
+
+    // Find the closest ancestor that exists
+    let mut missing = Vec::new();
+    let mut ancestor = path;
+    let mut parent = loop {
+        match open_ancestor(ancestor) {
+            Ok(dir) => break dir,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                missing.push(ancestor.file_name().ok_or(ErrorKind::NotFound)?);
+                ancestor = ancestor.parent().ok_or(ErrorKind::NotFound)?;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    let mut builder = DirBuilder::new();
+    // On `androideabi`, `mode_t` is not `u32` but `u16`
+    #[allow(clippy::unnecessary_cast)]
+    builder.mode(mode as u32);
+    let mut unsynced_parents = Vec::new();
+    for name in missing.into_iter().rev() {
+        match parent.create_dir_with(name, &builder) {
+            // If the directory was created concurrently, it may not have been synced yet: sync its
+            // parent anyway
+            Ok(()) => (),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err),
+        }
+        let dir = parent.open_dir(name)?;
+        unsynced_parents.push(imp::Dir::reopen(parent)?);
+        parent = dir;
+    }
+    Ok(unsynced_parents)
+}
+
+fn open_or_create_dir(parent: &Dir, path: &Path) -> Result<Dir> {
+    match parent.open_dir(path) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        result => return result,
+    }
+    match parent.create_dir(path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(err),
+    }
+    parent.open_dir(path)
+}
@@ -0,0 +1,238 @@
+use crate::OpenOptions;
+use crate::imp;
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options to configure the removal of temporary files left behind by
+/// [`AtomicWriteFile`](crate::AtomicWriteFile).
+///
+/// [`AtomicWriteFile`](crate::AtomicWriteFile) removes its temporary file when it is committed,
+/// discarded, or dropped. However, if the process is interrupted abruptly (without unwinding or
+/// running destructors), the temporary file may be left on the filesystem. By default, temporary
+/// files are named `.filename.XXXXXX`, where `filename` is the name of the destination file and
+/// `XXXXXX` is a random alphanumeric suffix (see
+/// [`OpenOptions::temporary_prefix()`](crate::OpenOptions::temporary_prefix) for how to customize
+/// this, and [`naming_from()`](CleanupOptions::naming_from) for how to find temporary files with
+/// custom names).
+///
+/// `CleanupOptions` can be used to find and remove these leftover temporary files, either for a
+/// specific destination file (using [`clean_file()`](CleanupOptions::clean_file)), or for all the
+/// files in a directory (using [`clean_dir()`](CleanupOptions::clean_dir)). Only regular files
+/// are removed. On Unix, files are removed using `unlinkat(2)` on a directory file descriptor, so
+/// that renaming the directory concurrently does not cause files in other directories to be
+/// removed.
+///
+/// **Warning:** temporary files are recognized by their name only. There is no way to distinguish
+/// a temporary file created by an [`AtomicWriteFile`](crate::AtomicWriteFile) that is still in
+/// use from an abandoned one, other than by their age. For this reason, only files that have not
+/// been modified in the last 24 hours are removed by default (see
+/// [`max_age()`](CleanupOptions::max_age)). [`clean_dir()`](CleanupOptions::clean_dir) is more
+/// conservative than [`clean_file()`](CleanupOptions::clean_file), so that unrelated files that
+/// happen to follow the same naming scheme (like `.bashrc.backup`) are not removed: see its
+/// documentation for details.
+///
+/// On Unix, temporary files can embed the ID of the process that created them (see
+/// [`pid_in_temporary_name()`](crate::unix::OpenOptionsExt::pid_in_temporary_name)). Such
+/// temporary files are never removed while their process is running. If their process is not
+/// running, or cannot be seen, they are removed according to their age, like other temporary
+/// files.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+/// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+/// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+/// use std::time::Duration;
+/// use atomic_write_file::CleanupOptions;
+///
+/// // Remove temporary files for "foo.txt" that have not been modified in the last hour
+/// let removed = CleanupOptions::new()
+///     .max_age(Duration::from_secs(60 * 60))
+///     .clean_file("foo.txt")?;
+/// for path in removed {
+///     println!("removed {path:?}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CleanupOptions {
+    max_age: Duration,
+    naming: imp::NameOptions,
+}
+
+impl CleanupOptions {
+    /// The default value of [`max_age()`](CleanupOptions::max_age).
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Create a set of options set to their default values.
+    ///
+    /// By default, only temporary files that were last modified at least
+    /// [`DEFAULT_MAX_AGE`](CleanupOptions::DEFAULT_MAX_AGE) (24 hours) ago are removed.
+    pub fn new() -> Self {
+        Self {
+            max_age: Self::DEFAULT_MAX_AGE,
+            naming: imp::NameOptions::new(),
+        }
+    }
+
+    /// Sets the minimum age of the temporary files to remove.
+    ///
+    /// Only temporary files that were last modified at least `max_age` ago are removed. Temporary
+    /// files that were modified more recently are assumed to be still in use, and are left
+    /// untouched. The default is [`DEFAULT_MAX_AGE`](CleanupOptions::DEFAULT_MAX_AGE) (24 hours).
+    /// Use [`Duration::ZERO`] to remove temporary files regardless of their age.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::time::Duration;
+    /// use atomic_write_file::CleanupOptions;
+    ///
+    /// let removed = CleanupOptions::new()
+    ///     .max_age(Duration::from_secs(24 * 60 * 60))
+    ///     .clean_dir(".")?;
+    /// # let _ = removed;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Recognizes temporary files using the naming settings of `options`.
+    ///
+    /// By default, temporary files are recognized using the default naming scheme
+    /// (`.filename.XXXXXX`). If the [`AtomicWriteFile`](crate::AtomicWriteFile)s were opened with
+    /// custom naming settings (like [`OpenOptions::temporary_prefix()`] or
+    /// [`OpenOptions::temporary_dir()`]), then the same settings must be used to find their
+    /// leftover temporary files. All other settings in `options` are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::time::Duration;
+    /// use atomic_write_file::CleanupOptions;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.temporary_dir(".tmp").temporary_suffix(".part");
+    ///
+    /// let file = options.open("foo.txt")?;
+    /// file.commit()?;
+    ///
+    /// let removed = CleanupOptions::new()
+    ///     .naming_from(&options)
+    ///     .max_age(Duration::from_secs(24 * 60 * 60))
+    ///     .clean_file("foo.txt")?;
+    /// # let _ = removed;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn naming_from(&mut self, options: &OpenOptions) -> &mut Self {
+        self.naming = options.inner.naming.clone();
+        self
+    }
+
+    /// Removes the leftover temporary files for the destination file at `path`.
+    ///
+    /// Returns the paths of the temporary files that were removed. The destination file itself is
+    /// never removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::time::Duration;
+    /// use atomic_write_file::CleanupOptions;
+    ///
+    /// let removed = CleanupOptions::new()
+    ///     .max_age(Duration::from_secs(24 * 60 * 60))
+    ///     .clean_file("foo.txt")?;
+    /// # let _ = removed;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn clean_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>> {
+        let path = path.as_ref();
+        let dir_path = path.parent().ok_or(ErrorKind::IsADirectory)?;
+        let name = path.file_name().ok_or(ErrorKind::IsADirectory)?;
+        self.clean(dir_path, Some(name))
+    }
+
+    /// Removes the leftover temporary files for all the destination files inside the directory
+    /// `dir`.
+    ///
+    /// Because the destination file is not known in advance, a file is only considered a leftover
+    /// temporary file if, in addition to following the naming scheme:
+    ///
+    /// * its name embeds the ID of the process that created it (see
+    ///   [`pid_in_temporary_name()`](crate::unix::OpenOptionsExt::pid_in_temporary_name)), or
+    /// * its destination file exists in `dir` (for example, `.foo.txt.XXXXXX` is only removed if
+    ///   `foo.txt` exists).
+    ///
+    /// Other files, like `.bashrc.backup` in a directory that does not contain `bashrc`, are left
+    /// untouched. As a consequence, the temporary files of destination files that were never
+    /// committed are not removed, unless they embed a process ID: use
+    /// [`clean_file()`](CleanupOptions::clean_file) to remove them.
+    ///
+    /// Returns the paths of the temporary files that were removed. Subdirectories are not
+    /// scanned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::time::Duration;
+    /// use atomic_write_file::CleanupOptions;
+    ///
+    /// let removed = CleanupOptions::new()
+    ///     .max_age(Duration::from_secs(24 * 60 * 60))
+    ///     .clean_dir(".")?;
+    /// # let _ = removed;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn clean_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.clean(dir.as_ref(), None)
+    }
+
+    fn clean(&self, dir_path: &Path, base_name: Option<&OsStr>) -> Result<Vec<PathBuf>> {
+        let removed = imp::remove_temporary_files(dir_path, base_name, &self.naming, self.max_age)?;
+        let temp_dir_path = match self.naming.dir {
+            Some(ref temp_dir) => dir_path.join(temp_dir),
+            None => dir_path.to_path_buf(),
+        };
+        Ok(removed
+            .into_iter()
+            .map(|name| temp_dir_path.join(name))
+            .collect())
+    }
+}
+
+impl Default for CleanupOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
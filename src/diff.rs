@@ -0,0 +1,503 @@
+//! Comparison of an [`AtomicWriteFile`] with its destination, without committing it.
+//!
+//! [`AtomicWriteFile::dry_run()`] can be used instead of [`AtomicWriteFile::commit()`] to find
+//! out what committing the file would change. It returns a [`Diff`] that describes the
+//! differences between the contents and metadata of the file and those of its destination, and
+//! then discards the file.
+//!
+//! # Examples
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+//! # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+//! # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+//! use atomic_write_file::OpenOptions;
+//! use atomic_write_file::diff::Contents;
+//! use std::io::Write;
+//!
+//! std::fs::write("diff.txt", "hello\n")?;
+//!
+//! let mut file = OpenOptions::new().read(true).open("diff.txt")?;
+//! file.write_all(b"world\n")?;
+//! let diff = file.dry_run()?;
+//!
+//! assert_eq!(
+//!     diff.contents(),
+//!     &Contents::Text("--- diff.txt\n+++ diff.txt\n@@ -1 +1 @@\n-hello\n+world\n".to_string())
+//! );
+//! // The file was discarded
+//! assert_eq!(std::fs::read_to_string("diff.txt")?, "hello\n");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::AtomicWriteFile;
+use crate::error;
+use crate::error::ResultExt;
+use crate::error::Stage;
+use std::fs::File;
+use std::fs::Metadata;
+use std::fs::Permissions;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::ops::Range;
+
+/// Number of unchanged lines shown before and after each change in unified diffs.
+const CONTEXT_LINES: usize = 3;
+
+/// Maximum number of inserted and deleted lines for which the shortest edit script is looked for
+/// in unified diffs. Finding it takes memory quadratic in the number of edits: beyond this limit,
+/// the changed lines are shown as replaced entirely.
+const MAX_EDIT_DISTANCE: usize = 1000;
+
+/// Label used in unified diffs for a destination that does not exist.
+const NO_FILE_LABEL: &str = "/dev/null";
+
+/// Differences between an [`AtomicWriteFile`] and its destination.
+///
+/// Returned by [`AtomicWriteFile::dry_run()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diff {
+    created: bool,
+    contents: Contents,
+    metadata: Vec<MetadataChange>,
+}
+
+impl Diff {
+    /// Returns `true` if committing the file would not change its destination: the destination
+    /// exists, and has the same contents and metadata as the file.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        !self.created && self.contents == Contents::Unchanged && self.metadata.is_empty()
+    }
+
+    /// Returns `true` if the destination does not exist, and would be created by committing the
+    /// file.
+    ///
+    /// In this case, [`contents()`](Diff::contents) compares the file with empty contents, and
+    /// [`metadata()`](Diff::metadata) is empty.
+    #[inline]
+    pub fn is_created(&self) -> bool {
+        self.created
+    }
+
+    /// Returns the differences between the contents of the destination and those of the file.
+    #[inline]
+    pub fn contents(&self) -> &Contents {
+        &self.contents
+    }
+
+    /// Returns the differences between the metadata of the destination and that of the file.
+    #[inline]
+    pub fn metadata(&self) -> &[MetadataChange] {
+        &self.metadata
+    }
+}
+
+/// Differences between the contents of an [`AtomicWriteFile`] and those of its destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Contents {
+    /// The contents are the same.
+    Unchanged,
+    /// The contents are both text (valid UTF-8 without NUL bytes), and differ as described by this
+    /// diff in unified format, with 3 lines of context.
+    ///
+    /// The diff is minimal, unless more than 1000 lines would need to be inserted or deleted: in
+    /// that case, all the lines between the first and the last change are shown as replaced.
+    Text(String),
+    /// The contents are binary, and differ at these byte ranges.
+    ///
+    /// Ranges are sorted and do not overlap. If the contents have different lengths, the bytes
+    /// past the end of the shortest one are considered changed.
+    Binary(Vec<Range<u64>>),
+}
+
+/// A difference between the metadata of an [`AtomicWriteFile`] and that of its destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetadataChange {
+    /// The permissions would change from `old` to `new`.
+    Permissions {
+        /// Permissions of the destination.
+        old: Permissions,
+        /// Permissions of the file.
+        new: Permissions,
+    },
+    /// The owner would change from `old_uid`:`old_gid` to `new_uid`:`new_gid`.
+    #[cfg(unix)]
+    Owner {
+        /// User ID of the destination.
+        old_uid: u32,
+        /// Group ID of the destination.
+        old_gid: u32,
+        /// User ID of the file.
+        new_uid: u32,
+        /// Group ID of the file.
+        new_gid: u32,
+    },
+}
+
+impl AtomicWriteFile {
+    /// Compares this file with its destination, and then discards it, leaving its path
+    /// unchanged.
+    ///
+    /// The returned [`Diff`] describes what [`commit()`](AtomicWriteFile::commit) would change:
+    /// the differences in contents (as a unified diff for text, or as changed byte ranges for
+    /// binary data) and in metadata (permissions and, on Unix, owner).
+    ///
+    /// The contents of both files are read in memory. Reading the contents of this file requires
+    /// [`OpenOptions::read()`](crate::OpenOptions::read) to be enabled. Returns an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput) if the file was opened without a name
+    /// (see [`OpenOptions::open_unnamed()`](crate::OpenOptions::open_unnamed)), or if the
+    /// destination exists but is not a regular file (for example a symlink, a FIFO or a device):
+    /// such destinations are never read, because a commit would replace them rather than write
+    /// through them, and because reading them may block or never end.
+    ///
+    /// See the [module-level documentation](crate::diff) for an example.
+    pub fn dry_run(mut self) -> Result<Diff> {
+        if self.temporary_file.name().is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "file was opened without a name: it has no destination to compare with",
+            ));
+        }
+        let diff = self.compare().map_err(|err| self.error(err))?;
+        self._discard()?;
+        Ok(diff)
+    }
+
+    fn compare(&self) -> error::Result<Diff> {
+        let file = &self.temporary_file.file;
+        let new_contents = read_contents(file).at_stage(Stage::Diff)?;
+        let new_metadata = file.metadata().at_stage(Stage::Diff)?;
+        let new_permissions = self.final_permissions(&new_metadata);
+
+        let destination = match self.temporary_file.open_destination()? {
+            Some(mut destination) => {
+                let metadata = destination.metadata().at_stage(Stage::Diff)?;
+                let mut contents = Vec::new();
+                destination
+                    .read_to_end(&mut contents)
+                    .at_stage(Stage::Diff)?;
+                Some((contents, metadata))
+            }
+            None => None,
+        };
+
+        let label = self.path.display().to_string();
+        let (old_contents, old_label, metadata) = match destination {
+            Some((ref contents, ref metadata)) => (
+                &contents[..],
+                &label[..],
+                metadata_changes(metadata, &new_metadata, new_permissions),
+            ),
+            None => (&[][..], NO_FILE_LABEL, Vec::new()),
+        };
+
+        Ok(Diff {
+            created: destination.is_none(),
+            contents: compare_contents(old_label, old_contents, &label, &new_contents),
+            metadata,
+        })
+    }
+
+    /// Returns the permissions that the file will have once committed, given its current
+    /// `metadata`. These differ from the current ones in secret mode, where the permissions are
+    /// only set at commit time.
+    fn final_permissions(&self, metadata: &Metadata) -> Permissions {
+        let permissions = metadata.permissions();
+        #[cfg(unix)]
+        if let Some(mode) = self.temporary_file.final_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            return Permissions::from_mode(permissions.mode() & !0o7777 | mode);
+        }
+        permissions
+    }
+}
+
+/// Reads the contents of `file`, from the start.
+fn read_contents(mut file: &File) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Returns the differences between the metadata `old` of the destination and the metadata `new`
+/// of the file, where `new_permissions` are the permissions that the file will be committed with.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn metadata_changes(
+    old: &Metadata,
+    new: &Metadata,
+    new_permissions: Permissions,
+) -> Vec<MetadataChange> {
+    let mut changes = Vec::new();
+    if old.permissions() != new_permissions {
+        changes.push(MetadataChange::Permissions {
+            old: old.permissions(),
+            new: new_permissions,
+        });
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if (old.uid(), old.gid()) != (new.uid(), new.gid()) {
+            changes.push(MetadataChange::Owner {
+                old_uid: old.uid(),
+                old_gid: old.gid(),
+                new_uid: new.uid(),
+                new_gid: new.gid(),
+            });
+        }
+    }
+    changes
+}
+
+fn compare_contents(old_label: &str, old: &[u8], new_label: &str, new: &[u8]) -> Contents {
+    if old == new {
+        return Contents::Unchanged;
+    }
+    match (as_text(old), as_text(new)) {
+        (Some(old), Some(new)) => Contents::Text(unified_diff(old_label, old, new_label, new)),
+        _ => Contents::Binary(changed_ranges(old, new)),
+    }
+}
+
+/// Returns `contents` as a string, if it looks like text.
+fn as_text(contents: &[u8]) -> Option<&str> {
+    if contents.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(contents).ok()
+}
+
+fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<Range<u64>> {
+    let len = old.len().max(new.len());
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for i in 0..len {
+        match (old.get(i) == new.get(i), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                ranges.push(s as u64..i as u64);
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        ranges.push(s as u64..len as u64);
+    }
+    ranges
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// An operation of an edit script, along with the positions in the old and new sequences where
+/// it applies.
+#[derive(Copy, Clone, Debug)]
+struct Edit {
+    op: Op,
+    old: usize,
+    new: usize,
+}
+
+/// Returns an edit script that turns `old` into `new`.
+///
+/// The common prefix and suffix of `old` and `new` are left unchanged, and the shortest edit
+/// script for the rest is found with Myers' algorithm. If more than [`MAX_EDIT_DISTANCE`] edits
+/// are needed, the rest is replaced entirely instead.
+fn edit_script<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    let middle = shortest_edit_script(old_middle, new_middle)
+        .unwrap_or_else(|| replacement(old_middle.len(), new_middle.len()));
+
+    let mut edits = Vec::with_capacity(prefix + middle.len() + suffix);
+    edits.extend((0..prefix).map(|i| Edit {
+        op: Op::Equal,
+        old: i,
+        new: i,
+    }));
+    edits.extend(middle.into_iter().map(|edit| Edit {
+        op: edit.op,
+        old: prefix + edit.old,
+        new: prefix + edit.new,
+    }));
+    edits.extend((0..suffix).map(|i| Edit {
+        op: Op::Equal,
+        old: old.len() - suffix + i,
+        new: new.len() - suffix + i,
+    }));
+    edits
+}
+
+/// Returns the shortest edit script that turns `old` into `new`, using Myers' algorithm, or
+/// `None` if it's longer than [`MAX_EDIT_DISTANCE`].
+fn shortest_edit_script<T: PartialEq>(old: &[T], new: &[T]) -> Option<Vec<Edit>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m).min(MAX_EDIT_DISTANCE as isize);
+    // `v[k + max + 1]` is the furthest `x` reached on diagonal `k`
+    let index = |k: isize| (k + max + 1) as usize;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // `trace[d]` is `v` before step `d`, restricted to the diagonals `-d..=d`: the others are not
+    // needed to backtrack through step `d`, and storing them would take O((n + m) * d) memory
+    let mut trace = Vec::new();
+    let mut found = false;
+
+    'search: for d in 0..=max {
+        trace.push(v[index(-d)..=index(d)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            } else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'search;
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, window) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        // Diagonals outside of `-d..=d` were not reached before step `d`
+        let v = |k: isize| {
+            if k.abs() <= d {
+                window[(k + d) as usize]
+            } else {
+                0
+            }
+        };
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v(k - 1) < v(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit {
+                op: Op::Equal,
+                old: x as usize,
+                new: y as usize,
+            });
+        }
+        if d > 0 {
+            let op = if x == prev_x { Op::Insert } else { Op::Delete };
+            edits.push(Edit {
+                op,
+                old: prev_x as usize,
+                new: prev_y as usize,
+            });
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// Returns an edit script that deletes all the `old_len` elements of the old sequence, and then
+/// inserts all the `new_len` elements of the new sequence.
+fn replacement(old_len: usize, new_len: usize) -> Vec<Edit> {
+    let deletes = (0..old_len).map(|i| Edit {
+        op: Op::Delete,
+        old: i,
+        new: 0,
+    });
+    let inserts = (0..new_len).map(|i| Edit {
+        op: Op::Insert,
+        old: old_len,
+        new: i,
+    });
+    deletes.chain(inserts).collect()
+}
+
+/// Returns the differences between `old` and `new` in unified format.
+fn unified_diff(old_label: &str, old: &str, new_label: &str, new: &str) -> String {
+    let old_lines = old.split_inclusive('\n').collect::<Vec<&str>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<&str>>();
+    let edits = edit_script(&old_lines, &new_lines);
+
+    // Group changes that are close to each other into hunks, each one a range of `edits`
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for (i, edit) in edits.iter().enumerate() {
+        if edit.op == Op::Equal {
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + 1 + CONTEXT_LINES).min(edits.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.end => hunk.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+
+    let mut diff = format!("--- {old_label}\n+++ {new_label}\n");
+    for hunk in hunks {
+        let edits = &edits[hunk];
+        let old_count = edits.iter().filter(|e| e.op != Op::Insert).count();
+        let new_count = edits.iter().filter(|e| e.op != Op::Delete).count();
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(edits[0].old, old_count),
+            hunk_range(edits[0].new, new_count),
+        ));
+        for edit in edits {
+            let (prefix, line) = match edit.op {
+                Op::Equal => (' ', old_lines[edit.old]),
+                Op::Delete => ('-', old_lines[edit.old]),
+                Op::Insert => ('+', new_lines[edit.new]),
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
+
+/// Formats the range of a hunk header, the same way as GNU diff.
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
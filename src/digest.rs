@@ -0,0 +1,549 @@
+use crate::OpenOptions;
+use std::fs::File;
+use std::io::IoSlice;
+use std::io::Result;
+
+#[cfg(feature = "digest")]
+use crate::AtomicWriteFile;
+#[cfg(feature = "digest")]
+use crate::Error;
+#[cfg(feature = "digest")]
+use crate::Stage;
+#[cfg(feature = "digest")]
+use crate::error::ResultExt;
+#[cfg(feature = "digest")]
+use crate::sha256::DIGEST_LEN;
+#[cfg(feature = "digest")]
+use crate::sha256::Hasher;
+#[cfg(feature = "digest")]
+use crate::sha256::to_hex;
+#[cfg(feature = "digest")]
+#[cfg(unix)]
+use std::ffi::CStr;
+#[cfg(feature = "digest")]
+use std::fs;
+#[cfg(feature = "digest")]
+use std::io;
+#[cfg(feature = "digest")]
+use std::io::ErrorKind;
+#[cfg(feature = "digest")]
+use std::io::Seek;
+#[cfg(feature = "digest")]
+use std::io::SeekFrom;
+#[cfg(feature = "digest")]
+use std::io::Write;
+#[cfg(feature = "digest")]
+use std::path::Path;
+#[cfg(feature = "digest")]
+use std::sync::Mutex;
+
+/// Name of the extended attribute used by [`DigestRecord::ExtendedAttribute`].
+#[cfg(feature = "digest")]
+#[cfg(unix)]
+const XATTR_NAME: &CStr = c"user.checksum.sha256";
+
+/// Extension of the sidecar files used by [`DigestRecord::Sidecar`].
+#[cfg(feature = "digest")]
+const SIDECAR_EXTENSION: &str = ".sha256";
+
+/// Where to record the SHA-256 digest of an [`AtomicWriteFile`] when it's committed.
+///
+/// See [`OpenOptions::record_digest()`] for details. This enum is only available with the
+/// `digest` feature enabled.
+#[cfg(feature = "digest")]
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DigestRecord {
+    /// Record the digest in the `user.checksum.sha256` extended attribute of the file, as a
+    /// lowercase hexadecimal string.
+    ///
+    /// The attribute is set on the temporary file before it's renamed, so the file and its digest
+    /// are committed atomically. Extended attributes are only supported on Linux and macOS, and
+    /// not all filesystems support them: if they're not supported, committing the file fails.
+    ExtendedAttribute,
+    /// Record the digest in a sidecar file next to the file, with the same name followed by
+    /// `.sha256` (for example, `foo.txt.sha256` for `foo.txt`).
+    ///
+    /// The sidecar file is written with its own [`AtomicWriteFile`], opened with the same
+    /// [`OpenOptions`] as the file, in the same format used by `sha256sum(1)`, so it can also be
+    /// checked with `sha256sum --check`. The sidecar file is committed right before the file is
+    /// renamed, and if that fails, the file is not committed. If the process is interrupted
+    /// between the two commits, or if the file fails to be renamed, the sidecar file may refer to
+    /// the new contents while the file has its previous contents, and
+    /// [`verify()`](DigestRecord::verify) will report a mismatch.
+    Sidecar,
+}
+
+#[cfg(feature = "digest")]
+impl DigestRecord {
+    /// Hashes the file at `path`, and checks that its digest matches the one recorded for it.
+    ///
+    /// Returns `Ok(true)` if the digests match, and `Ok(false)` if they don't. Returns an error of
+    /// kind [`NotFound`](std::io::ErrorKind::NotFound) if no digest was recorded for the file
+    /// (that is, if the extended attribute or the sidecar file is missing), or of kind
+    /// [`InvalidData`](std::io::ErrorKind::InvalidData) if the recorded digest is malformed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::DigestRecord;
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new()
+    ///     .compute_digest(true)
+    ///     .record_digest(Some(DigestRecord::Sidecar))
+    ///     .open("foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// file.commit()?;
+    ///
+    /// assert!(DigestRecord::Sidecar.verify("foo.txt")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verify<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let path = path.as_ref();
+        let recorded = match self {
+            Self::ExtendedAttribute => get_xattr(path)?,
+            Self::Sidecar => {
+                let mut sidecar_path = path.as_os_str().to_os_string();
+                sidecar_path.push(SIDECAR_EXTENSION);
+                let contents = fs::read(sidecar_path)?;
+                // The digest is the first field of the first line
+                let end = contents
+                    .iter()
+                    .position(u8::is_ascii_whitespace)
+                    .unwrap_or(contents.len());
+                contents[..end].to_vec()
+            }
+        };
+        if recorded.len() != 2 * DIGEST_LEN || !recorded.iter().all(u8::is_ascii_hexdigit) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "recorded digest is malformed",
+            ));
+        }
+        let (hasher, _) = hash_contents(&File::open(path)?)?;
+        let actual = to_hex(&hasher.finalize());
+        Ok(actual.as_bytes().eq_ignore_ascii_case(&recorded))
+    }
+}
+
+#[cfg(feature = "digest")]
+#[cfg(unix)]
+fn set_xattr(file: &File, value: &str) -> Result<()> {
+    crate::imp::xattr::set(file, XATTR_NAME, value.as_bytes())
+}
+
+#[cfg(feature = "digest")]
+#[cfg(unix)]
+fn get_xattr(path: &Path) -> Result<Vec<u8>> {
+    // Allow for some extra bytes, so that longer values are reported as malformed instead of
+    // causing an `ERANGE` error
+    crate::imp::xattr::get(path, XATTR_NAME, 4 * DIGEST_LEN)
+}
+
+#[cfg(feature = "digest")]
+#[cfg(not(unix))]
+fn set_xattr(_file: &File, _value: &str) -> Result<()> {
+    Err(xattr_unsupported())
+}
+
+#[cfg(feature = "digest")]
+#[cfg(not(unix))]
+fn get_xattr(_path: &Path) -> Result<Vec<u8>> {
+    Err(xattr_unsupported())
+}
+
+#[cfg(feature = "digest")]
+#[cfg(not(unix))]
+fn xattr_unsupported() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "extended attributes are not supported on this platform",
+    )
+}
+
+#[cfg(feature = "digest")]
+impl OpenOptions {
+    /// Sets the option to compute the SHA-256 digest of the file while it's written.
+    ///
+    /// When this option is enabled, the contents written through [`Write`] (and,
+    /// on Unix, [`FileExt`](std::os::unix::fs::FileExt)) are hashed as they're written, so that
+    /// [`AtomicWriteFile::digest()`] does not need to read the file back. This works as long as
+    /// the contents are written sequentially, from the start of the file to the end; if the file
+    /// is written out of order (for example, after a seek), or modified through the underlying
+    /// [`File`], the digest is computed by reading the file back.
+    ///
+    /// Note that writes performed directly on the underlying [`File`] (for example, through
+    /// [`as_file()`](AtomicWriteFile::as_file)) are not tracked. If they change the length of the
+    /// file, this is detected and the file is read back; if they overwrite existing contents
+    /// without changing the length, the digest will not reflect them.
+    ///
+    /// The default is `false`. This method is only available with the `digest` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new().compute_digest(true).open("foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// let digest = file.digest()?;
+    /// println!("SHA-256: {digest:02x?}");
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compute_digest(&mut self, compute_digest: bool) -> &mut Self {
+        self.compute_digest = compute_digest;
+        self
+    }
+
+    /// Sets where to record the SHA-256 digest of the file when it's committed.
+    ///
+    /// If `Some`, the digest of the contents (as returned by [`AtomicWriteFile::digest()`]) is
+    /// recorded when the file is committed, either in an extended attribute or in a sidecar file.
+    /// See [`DigestRecord`] for the details of each option. The recorded digest can be checked
+    /// later with [`DigestRecord::verify()`].
+    ///
+    /// Computing the digest may require reading the file back (see
+    /// [`AtomicWriteFile::digest()`]), in which case [`read()`](OpenOptions::read) must be
+    /// enabled. To avoid that, enable [`compute_digest()`](OpenOptions::compute_digest) too.
+    ///
+    /// The default is `None`. This method is only available with the `digest` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::DigestRecord;
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new()
+    ///     .compute_digest(true)
+    ///     .record_digest(Some(DigestRecord::Sidecar))
+    ///     .open("foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// file.commit()?; // also writes "foo.txt.sha256"
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record_digest(&mut self, record_digest: Option<DigestRecord>) -> &mut Self {
+        self.record_digest = record_digest;
+        self
+    }
+
+    /// Returns the options to open the sidecar file with, if [`DigestRecord::Sidecar`] is
+    /// requested: the same options (so that, for example, the sidecar file gets the same
+    /// permissions and temporary file names), without recording the digest of the sidecar file.
+    pub(crate) fn sidecar_options(&self) -> Option<OpenOptions> {
+        if self.record_digest != Some(DigestRecord::Sidecar) {
+            return None;
+        }
+        let mut options = self.clone();
+        options.compute_digest = false;
+        options.record_digest = None;
+        Some(options)
+    }
+}
+
+#[cfg(feature = "digest")]
+impl AtomicWriteFile {
+    /// Returns the SHA-256 digest of the contents of this file.
+    ///
+    /// If [`OpenOptions::compute_digest()`] was enabled, and the contents have been written
+    /// sequentially, the digest is returned without accessing the file. Otherwise, the contents
+    /// of the file are read back and hashed: in this case, the file must have been opened with
+    /// [`OpenOptions::read()`] enabled.
+    ///
+    /// This method is only available with the `digest` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new().read(true).open("foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// assert_eq!(
+    ///     file.digest()?[..4],
+    ///     [0x2c, 0xf2, 0x4d, 0xba],
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn digest(&mut self) -> Result<[u8; DIGEST_LEN]> {
+        self.compute_digest()
+            .at_stage(Stage::ComputeDigest)
+            .map_err(|err| self.error(err))
+    }
+
+    fn compute_digest(&mut self) -> Result<[u8; DIGEST_LEN]> {
+        let file = &mut self.temporary_file.file;
+        if let Some(ref tracker) = self.digest {
+            if let Some(digest) = tracker.digest(file)? {
+                return Ok(digest);
+            }
+        }
+        let (hasher, len) = hash_contents(file)?;
+        if let Some(ref tracker) = self.digest {
+            tracker.reset(hasher.clone(), len);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Records the digest of this file, as requested by [`OpenOptions::record_digest()`].
+    ///
+    /// This must be called before the file is renamed. If a sidecar file is requested, it's
+    /// returned, and must be committed before this file is renamed.
+    pub(crate) fn record_digest(&mut self) -> Result<Option<AtomicWriteFile>> {
+        let record_digest = match self.record_digest {
+            Some(record_digest) => record_digest,
+            None => return Ok(None),
+        };
+        let digest = to_hex(&self.digest()?);
+        match record_digest {
+            DigestRecord::ExtendedAttribute => {
+                set_xattr(&self.temporary_file.file, &digest)
+                    .at_stage(Stage::RecordDigest)
+                    .map_err(|err| self.error(err))?;
+                Ok(None)
+            }
+            DigestRecord::Sidecar => {
+                let name = self
+                    .temporary_file
+                    .name()
+                    .expect("file must have a name to be committed");
+                let mut sidecar_name = name.to_os_string();
+                sidecar_name.push(SIDECAR_EXTENSION);
+                let options = self
+                    .sidecar_options
+                    .as_ref()
+                    .expect("sidecar options must be set when a sidecar is requested");
+                let mut sidecar = match self.directory() {
+                    Some(dir) => options.open_in(&dir, &sidecar_name)?,
+                    None => options.open(self.path.with_file_name(&sidecar_name))?,
+                };
+                writeln!(sidecar, "{digest}  {}", name.to_string_lossy())?;
+                Ok(Some(sidecar))
+            }
+        }
+    }
+
+    /// Saves the contents of this file to its path, but only if their SHA-256 digest is
+    /// `expected`.
+    ///
+    /// If the digest of the contents (as returned by [`digest()`](AtomicWriteFile::digest)) does
+    /// not match `expected`, the file is discarded and an error of kind
+    /// [`InvalidData`](std::io::ErrorKind::InvalidData) is returned, with
+    /// [`Stage::VerifyDigest`] as its [stage](crate::Error::stage). The original file (if any) is
+    /// left unchanged.
+    ///
+    /// This method is only available with the `digest` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::ErrorKind;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new().compute_digest(true).open("foo.txt")?;
+    /// file.write_all(b"corrupted")?;
+    /// let err = file.commit_if_digest(&[0; 32]).expect_err("commit should fail");
+    /// assert_eq!(err.kind(), ErrorKind::InvalidData);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn commit_if_digest(mut self, expected: &[u8; DIGEST_LEN]) -> Result<()> {
+        if self.digest()? != *expected {
+            self._discard()?;
+            let err = io::Error::new(
+                ErrorKind::InvalidData,
+                "contents do not match the expected digest",
+            );
+            return Err(self.error(Error::new(Stage::VerifyDigest, err)));
+        }
+        self._commit().map(drop)
+    }
+}
+
+/// Hashes the contents of `file`, from the start, returning the hasher and the number of bytes
+/// hashed. The position of `file` is left unchanged.
+#[cfg(feature = "digest")]
+fn hash_contents(mut file: &File) -> Result<(Hasher, u64)> {
+    let position = file.stream_position()?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Hasher::new();
+    let result = io::copy(&mut file, &mut hasher);
+    file.seek(SeekFrom::Start(position))?;
+    Ok((hasher, result?))
+}
+
+/// Tracks the contents written to a file, so that their digest can be computed incrementally.
+#[cfg(feature = "digest")]
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    state: Mutex<State>,
+}
+
+#[cfg(feature = "digest")]
+#[derive(Debug)]
+struct State {
+    hasher: Hasher,
+    /// Number of bytes hashed so far, from the start of the file.
+    len: u64,
+    /// Whether all the writes so far have been sequential; if not, `hasher` is no longer valid.
+    sequential: bool,
+}
+
+#[cfg(feature = "digest")]
+impl Tracker {
+    pub(crate) fn for_options(options: &OpenOptions) -> Option<Self> {
+        if !options.compute_digest {
+            return None;
+        }
+        Some(Self {
+            state: Mutex::new(State {
+                hasher: Hasher::new(),
+                len: 0,
+                sequential: true,
+            }),
+        })
+    }
+
+    /// Performs `write` on `file`, and hashes the data that was written.
+    ///
+    /// `data` is the data passed to `write`, and `offset` is where it's written to, or `None` if
+    /// it's written at the current position of `file`. `write` must return the number of bytes
+    /// written, which must be a prefix of `data`.
+    pub(crate) fn write<F>(
+        &self,
+        mut file: &File,
+        offset: Option<u64>,
+        data: &[IoSlice<'_>],
+        write: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&File) -> Result<usize>,
+    {
+        // Hold the lock while writing, so that concurrent writes are hashed in the same order as
+        // they're performed
+        let mut state = self.state.lock().expect("digest state poisoned");
+        let written = match write(file) {
+            Ok(written) => written,
+            Err(err) => {
+                // Some data may have been written anyway (for example, by `write_all()`)
+                state.sequential = false;
+                return Err(err);
+            }
+        };
+        let offset = match offset {
+            Some(offset) => offset,
+            // Use the position after the write, as the position before the write may not be where
+            // the data was written (for example, if the file was opened with `O_APPEND`)
+            None => match file.stream_position() {
+                Ok(end) => end - written as u64,
+                Err(err) => {
+                    state.sequential = false;
+                    return Err(err);
+                }
+            },
+        };
+        state.update(offset, data, written);
+        Ok(written)
+    }
+
+    /// Returns the digest of the contents of `file`, if it's known.
+    fn digest(&self, file: &File) -> Result<Option<[u8; DIGEST_LEN]>> {
+        let state = self.state.lock().expect("digest state poisoned");
+        // The file may have been truncated or extended through the underlying `File`
+        if !state.sequential || file.metadata()?.len() != state.len {
+            return Ok(None);
+        }
+        Ok(Some(state.hasher.clone().finalize()))
+    }
+
+    /// Replaces the digest state with `hasher`, which has hashed the first `len` bytes of the file.
+    fn reset(&self, hasher: Hasher, len: u64) {
+        let mut state = self.state.lock().expect("digest state poisoned");
+        *state = State {
+            hasher,
+            len,
+            sequential: true,
+        };
+    }
+}
+
+#[cfg(feature = "digest")]
+impl State {
+    fn update(&mut self, offset: u64, data: &[IoSlice<'_>], written: usize) {
+        if written == 0 {
+            return;
+        }
+        if !self.sequential || offset != self.len {
+            self.sequential = false;
+            return;
+        }
+        let mut remaining = written;
+        for buf in data {
+            let len = buf.len().min(remaining);
+            self.hasher.update(&buf[..len]);
+            remaining -= len;
+            if remaining == 0 {
+                break;
+            }
+        }
+        self.len += written as u64;
+    }
+}
+
+// An enum without variants, so that it can never be constructed
+#[cfg(not(feature = "digest"))]
+#[derive(Debug)]
+pub(crate) enum Tracker {}
+
+#[cfg(not(feature = "digest"))]
+impl Tracker {
+    #[inline]
+    pub(crate) fn for_options(_options: &OpenOptions) -> Option<Self> {
+        None
+    }
+
+    #[inline]
+    pub(crate) fn write<F>(
+        &self,
+        _file: &File,
+        _offset: Option<u64>,
+        _data: &[IoSlice<'_>],
+        _write: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&File) -> Result<usize>,
+    {
+        match *self {}
+    }
+}
@@ -1,18 +1,31 @@
 use crate::imp;
+use std::io::Result;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
 
-/// A borrowed reference to the directory containing an
-/// [`AtomicWriteFile`](crate::AtomicWriteFile).
+/// A reference to a directory that can contain [`AtomicWriteFile`](crate::AtomicWriteFile)s.
 ///
-/// This can be obtained via [`AtomicWriteFile::directory()`](crate::AtomicWriteFile::directory).
-/// The purpose of this struct is to allow you to obtain the directory file descriptor, without
-/// having to open it through a call to `open(2)`.
+/// This can be obtained in two ways:
 ///
-/// This struct supports only two operations:
+/// - via [`AtomicWriteFile::directory()`](crate::AtomicWriteFile::directory), which returns the
+///   directory containing an [`AtomicWriteFile`](crate::AtomicWriteFile), without having to open
+///   it through a call to `open(2)`;
+/// - via [`Directory::open()`], which opens a directory once, so that it can be used to open many
+///   [`AtomicWriteFile`](crate::AtomicWriteFile)s through
+///   [`OpenOptions::open_in()`](crate::OpenOptions::open_in), without resolving the path of the
+///   directory every time.
+///
+/// This struct supports only three operations:
+/// - opening files inside the directory through
+///   [`OpenOptions::open_in()`](crate::OpenOptions::open_in)
 /// - conversion to a borrowed directory file descriptor through
 ///   [`AsFd::as_fd()`](std::os::fd::AsFd::as_fd)
 /// - conversion to a raw directory file descriptor through
 ///   [`AsRawFd::as_raw_fd()`](std::os::fd::AsRawFd::as_raw_fd)
 ///
+/// Cloning a `Directory` is cheap: clones share the same underlying directory file descriptor.
+///
 /// Directory file descriptors are not available on all platforms. See
 /// [`AtomicWriteFile::directory()`](crate::AtomicWriteFile::directory) for more details.
 ///
@@ -39,15 +52,59 @@ use crate::imp;
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct Directory<'a> {
-    #[cfg_attr(not(any(unix, target_os = "wasi")), allow(dead_code))]
-    inner: &'a imp::Dir,
+    pub(crate) inner: Arc<imp::Dir>,
+    marker: PhantomData<&'a imp::Dir>,
 }
 
 impl<'a> Directory<'a> {
-    pub(crate) fn new(inner: &'a imp::Dir) -> Self {
-        Self { inner }
+    pub(crate) fn new(inner: &'a Arc<imp::Dir>) -> Self {
+        Self {
+            inner: Arc::clone(inner),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl Directory<'static> {
+    /// Opens the directory at `path`.
+    ///
+    /// The returned `Directory` keeps the directory open until it is dropped, and can be used to
+    /// open [`AtomicWriteFile`](crate::AtomicWriteFile)s inside it through
+    /// [`OpenOptions::open_in()`](crate::OpenOptions::open_in). Files opened this way keep
+    /// referring to the same directory even if `path` is later renamed or replaced.
+    ///
+    /// Returns an error of kind [`Unsupported`](std::io::ErrorKind::Unsupported) on platforms
+    /// that do not support directory file descriptors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// # #[cfg(unix)]
+    /// # {
+    /// use atomic_write_file::Directory;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let dir = Directory::open(".")?;
+    /// let options = OpenOptions::new();
+    /// for name in ["foo.txt", "bar.txt"] {
+    ///     let file = options.open_in(&dir, name)?;
+    ///     file.commit()?;
+    /// }
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            inner: Arc::new(imp::Dir::open(path)?),
+            marker: PhantomData,
+        })
     }
 }
 
@@ -0,0 +1,342 @@
+use crate::AtomicWriteFile;
+use std::error;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The operation that was being performed when an [`Error`] occurred.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Opening the directory that contains the destination file.
+    OpenDirectory,
+    /// Creating the missing directories that contain the destination file (see
+    /// [`OpenOptions::create_parents()`](crate::OpenOptions::create_parents)).
+    CreateDirectory,
+    /// Opening or creating the directory for temporary files (see
+    /// [`OpenOptions::temporary_dir()`](crate::OpenOptions::temporary_dir)).
+    OpenTemporaryDirectory,
+    /// Checking that the destination file can be replaced (see
+    /// [`OpenOptions::check_destination()`](crate::OpenOptions::check_destination)).
+    CheckDestination,
+    /// Creating the temporary file.
+    CreateTemporaryFile,
+    /// Copying the permissions of the destination file to the temporary file (`fchmod(2)` on
+    /// Unix).
+    CopyPermissions,
+    /// Copying the owner of the destination file to the temporary file (`fchown(2)` on Unix).
+    CopyOwner,
+    /// Setting the permissions of the temporary file (see
+    /// [`OpenOptions::permissions()`](crate::OpenOptions::permissions)).
+    SetPermissions,
+    /// Setting an explicit owner on the temporary file (`fchown(2)` on Unix).
+    SetOwner,
+    /// Flushing the contents of the temporary file to the storage device (`fsync(2)` on Unix).
+    Sync,
+    /// Giving a name to an anonymous temporary file (`linkat(2)` on Linux).
+    Link,
+    /// Checking that the directory that contains the destination file was not moved or removed
+    /// (see [`OpenOptions::check_directory()`](crate::OpenOptions::check_directory)).
+    CheckDirectory,
+    /// Renaming the temporary file to the destination file (`renameat(2)` on Unix).
+    Rename,
+    /// Flushing the directory that contains the destination file to the storage device.
+    SyncDirectory,
+    /// Writing the contents of the temporary file directly to the destination file, because it
+    /// could not be replaced (see
+    /// [`OpenOptions::in_place_fallback()`](crate::OpenOptions::in_place_fallback)).
+    WriteInPlace,
+    /// Removing the temporary file.
+    RemoveTemporaryFile,
+    /// Computing the digest of the contents of the temporary file.
+    ComputeDigest,
+    /// Checking that the digest of the contents of the temporary file matches the expected one.
+    VerifyDigest,
+    /// Recording the digest of the contents of the temporary file (see
+    #[cfg_attr(
+        feature = "digest",
+        doc = "[`OpenOptions::record_digest()`](crate::OpenOptions::record_digest))."
+    )]
+    #[cfg_attr(not(feature = "digest"), doc = "`OpenOptions::record_digest()`).")]
+    RecordDigest,
+    /// Validating the contents of the temporary file (see
+    /// [`AtomicWriteFile::commit_with_validation()`]).
+    Validate,
+    /// Comparing the temporary file with the destination file (see
+    /// [`AtomicWriteFile::dry_run()`](crate::AtomicWriteFile::dry_run)).
+    Diff,
+}
+
+impl Stage {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::OpenDirectory => "failed to open directory",
+            Self::CreateDirectory => "failed to create directory",
+            Self::OpenTemporaryDirectory => "failed to open directory for temporary files",
+            Self::CheckDestination => "failed to check destination file",
+            Self::CreateTemporaryFile => "failed to create temporary file",
+            Self::CopyPermissions => "failed to copy permissions to temporary file",
+            Self::CopyOwner => "failed to copy owner to temporary file",
+            Self::SetPermissions => "failed to set permissions of temporary file",
+            Self::SetOwner => "failed to set owner of temporary file",
+            Self::Sync => "failed to sync temporary file",
+            Self::Link => "failed to link temporary file",
+            Self::CheckDirectory => "failed to check directory",
+            Self::Rename => "failed to rename temporary file",
+            Self::SyncDirectory => "failed to sync directory",
+            Self::WriteInPlace => "failed to write destination file in place",
+            Self::RemoveTemporaryFile => "failed to remove temporary file",
+            Self::ComputeDigest => "failed to compute digest of temporary file",
+            Self::VerifyDigest => "failed to verify digest of temporary file",
+            Self::RecordDigest => "failed to record digest of temporary file",
+            Self::Validate => "failed to validate temporary file",
+            Self::Diff => "failed to compare temporary file with destination",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// An error from an [`AtomicWriteFile`](crate::AtomicWriteFile) operation, with details about
+/// what failed.
+///
+/// The methods of [`OpenOptions`](crate::OpenOptions) and
+/// [`AtomicWriteFile`](crate::AtomicWriteFile) return [`std::io::Error`]s, for compatibility
+/// with [`std::fs::File`]. When an operation on the filesystem fails, the returned
+/// [`std::io::Error`] has the same [`kind()`](std::io::Error::kind) as the underlying error, and
+/// wraps an `Error` that describes which [stage](Stage) of the operation failed, and which files
+/// were involved. The `Error` can be obtained with [`std::io::Error::get_ref()`] or
+/// [`std::io::Error::into_inner()`], followed by a downcast. The [`Display`](fmt::Display)
+/// implementation of the [`std::io::Error`] also includes these details.
+///
+/// Note that, because of the wrapping, [`std::io::Error::raw_os_error()`] returns `None`: use
+/// [`Error::raw_os_error()`] instead.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+/// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+/// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+/// use atomic_write_file::AtomicWriteFile;
+/// use atomic_write_file::Error;
+/// use atomic_write_file::Stage;
+///
+/// let err = AtomicWriteFile::open("does/not/exist").expect_err("open should fail");
+/// let details = err
+///     .get_ref()
+///     .and_then(|err| err.downcast_ref::<Error>())
+///     .expect("no details available");
+/// assert_eq!(details.stage(), Stage::OpenDirectory);
+/// assert_eq!(details.path(), Some("does/not/exist".as_ref()));
+/// println!("{err}");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Error {
+    stage: Stage,
+    path: Option<PathBuf>,
+    temporary_name: Option<OsString>,
+    destination_replaced: bool,
+    source: io::Error,
+}
+
+impl Error {
+    pub(crate) fn new<E: Into<io::Error>>(stage: Stage, source: E) -> Self {
+        Self {
+            stage,
+            path: None,
+            temporary_name: None,
+            destination_replaced: false,
+            source: source.into(),
+        }
+    }
+
+    /// Sets the destination path, unless already set.
+    pub(crate) fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    /// Sets the name of the temporary file, unless already set.
+    pub(crate) fn with_temporary_name(mut self, temporary_name: Option<&OsStr>) -> Self {
+        if self.temporary_name.is_none() {
+            self.temporary_name = temporary_name.map(OsStr::to_os_string);
+        }
+        self
+    }
+
+    /// Records whether the error occurred after the destination file was replaced.
+    pub(crate) fn with_destination_replaced(mut self, destination_replaced: bool) -> Self {
+        self.destination_replaced |= destination_replaced;
+        self
+    }
+
+    /// Returns the operation that failed.
+    #[inline]
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Returns the path of the destination file, as it was passed when opening the
+    /// [`AtomicWriteFile`](crate::AtomicWriteFile).
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the name of the temporary file, if it had one at the time of the error.
+    ///
+    /// The temporary file is located in the same directory as the destination file, unless
+    /// [`OpenOptions::temporary_dir()`](crate::OpenOptions::temporary_dir) was used.
+    #[inline]
+    pub fn temporary_name(&self) -> Option<&OsStr> {
+        self.temporary_name.as_deref()
+    }
+
+    /// Returns `true` if the error occurred after the destination file was already replaced.
+    ///
+    /// This can happen if the temporary file was renamed successfully, but the directory could
+    /// not be synced afterwards. In that case, the new contents are visible at the destination
+    /// path, but they may not survive a crash.
+    #[inline]
+    pub fn is_destination_replaced(&self) -> bool {
+        self.destination_replaced
+    }
+
+    /// Returns the kind of the underlying error.
+    #[inline]
+    pub fn kind(&self) -> ErrorKind {
+        self.source.kind()
+    }
+
+    /// Returns the OS error code (`errno` on Unix) of the underlying error, if any.
+    #[inline]
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// Returns a reference to the underlying error.
+    #[inline]
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// Returns the underlying error.
+    #[inline]
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.stage.description())?;
+        if let Some(ref temporary_name) = self.temporary_name {
+            write!(f, " {temporary_name:?}")?;
+        }
+        if let Some(ref path) = self.path {
+            write!(f, " for {path:?}")?;
+        }
+        write!(f, ": {}", self.source)?;
+        if self.destination_replaced {
+            f.write_str(" (the destination file was already replaced)")?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Error returned by [`AtomicWriteFile::try_commit()`], containing the file that failed to be
+/// committed.
+///
+/// The [`AtomicWriteFile`] in [`file`](CommitError::file) is still open, and its contents are
+/// intact: it can be committed again, discarded, or read (if it was opened with
+/// [`OpenOptions::read()`](crate::OpenOptions::read)). If it is dropped, its temporary file is
+/// removed as usual.
+///
+/// The only exception is when the error occurred after the destination file was already replaced
+/// (see [`Error::is_destination_replaced()`]): in that case, [`file`](CommitError::file) is
+/// already committed, and committing it again does nothing.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+/// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+/// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+/// use atomic_write_file::AtomicWriteFile;
+/// use std::io::Write;
+///
+/// let mut file = AtomicWriteFile::open("foo.txt")?;
+/// file.write_all(b"hello")?;
+/// if let Err(err) = file.try_commit() {
+///     eprintln!("commit failed: {}; trying again", err.error);
+///     err.file.commit()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CommitError {
+    /// The error that caused the commit to fail.
+    pub error: io::Error,
+    /// The file that failed to be committed.
+    pub file: AtomicWriteFile,
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl error::Error for CommitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<CommitError> for io::Error {
+    fn from(err: CommitError) -> Self {
+        err.error
+    }
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+/// Attaches a [`Stage`] to errors.
+pub(crate) trait ResultExt<T> {
+    fn at_stage(self, stage: Stage) -> Result<T>;
+}
+
+impl<T, E: Into<io::Error>> ResultExt<T> for std::result::Result<T, E> {
+    #[inline]
+    fn at_stage(self, stage: Stage) -> Result<T> {
+        self.map_err(|err| Error::new(stage, err))
+    }
+}
@@ -1,14 +1,22 @@
-use rand::Rng;
-use rand::distr::Alphanumeric;
+use crate::error;
+use crate::error::ResultExt;
+use crate::error::Stage;
+use crate::imp::NameOptions;
+use crate::imp::RandomName;
+use crate::imp::copy_contents;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
+use std::fs::Permissions;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Result;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 
 fn is_a_directory() -> Error {
     // TODO Use `ErrorKind::IsADirectory` once it is stabilized
@@ -18,11 +26,25 @@ fn is_a_directory() -> Error {
 #[derive(Clone, Debug)]
 pub(crate) struct OpenOptions {
     pub(crate) read: bool,
+    pub(crate) naming: NameOptions,
+    pub(crate) remove_stale: Option<Duration>,
+    pub(crate) permissions: Option<Permissions>,
+    pub(crate) metadata_from: Option<PathBuf>,
+    pub(crate) check_destination: bool,
+    pub(crate) create_parents: bool,
 }
 
 impl OpenOptions {
     pub(crate) fn new() -> Self {
-        Self { read: false }
+        Self {
+            read: false,
+            naming: NameOptions::new(),
+            remove_stale: None,
+            permissions: None,
+            metadata_from: None,
+            check_destination: false,
+            create_parents: false,
+        }
     }
 }
 
@@ -35,22 +57,67 @@ impl Default for OpenOptions {
 #[derive(Debug)]
 pub(crate) struct TemporaryFile {
     pub(crate) temp_path: PathBuf,
-    pub(crate) dest_path: PathBuf,
+    pub(crate) dir_path: PathBuf,
+    pub(crate) name: OsString,
     pub(crate) file: File,
+    renamed: bool,
 }
 
 impl TemporaryFile {
-    pub(crate) fn open(opts: &OpenOptions, path: &Path) -> Result<Self> {
-        let dest_path = path.to_owned();
-        let dir_path = dest_path.parent().ok_or_else(is_a_directory)?;
-        let name = dest_path
+    pub(crate) fn open(opts: &OpenOptions, path: &Path) -> error::Result<Self> {
+        let dir_path = path
+            .parent()
+            .ok_or_else(is_a_directory)
+            .at_stage(Stage::OpenDirectory)?;
+        let name = path
             .file_name()
-            .ok_or_else(is_a_directory)?
-            .to_os_string();
+            .ok_or_else(is_a_directory)
+            .at_stage(Stage::OpenDirectory)?;
+        Self::open_with_name(opts, dir_path, name)
+    }
+
+    /// Opens a temporary file in `dir_path`, without a destination name. The name must be set with
+    /// [`set_name()`](Self::set_name) before the file can be renamed.
+    pub(crate) fn open_unnamed(opts: &OpenOptions, dir_path: &Path) -> error::Result<Self> {
+        Self::open_with_name(opts, dir_path, OsStr::new(""))
+    }
+
+    fn open_with_name(opts: &OpenOptions, dir_path: &Path, name: &OsStr) -> error::Result<Self> {
+        opts.naming
+            .validate()
+            .at_stage(Stage::CreateTemporaryFile)?;
 
-        let mut random_name = RandomName::new(&name);
+        if opts.create_parents {
+            // Directories cannot be synced on this platform
+            fs::create_dir_all(dir_path).at_stage(Stage::CreateDirectory)?;
+        }
+
+        if opts.check_destination && !name.is_empty() {
+            check_destination(&dir_path.join(name)).at_stage(Stage::CheckDestination)?;
+        }
+
+        let name = name.to_os_string();
+        let temp_dir_path = match opts.naming.dir {
+            Some(ref temp_dir) => {
+                let temp_dir_path = dir_path.join(temp_dir);
+                match fs::create_dir(&temp_dir_path) {
+                    Ok(()) => (),
+                    Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+                    Err(err) => return Err(err).at_stage(Stage::OpenTemporaryDirectory),
+                }
+                temp_dir_path
+            }
+            None => dir_path.to_owned(),
+        };
+
+        if let Some(max_age) = opts.remove_stale {
+            // Best-effort: errors are ignored
+            let _ = remove_temporary_files(dir_path, Some(&name), &opts.naming, max_age);
+        }
+
+        let mut random_name = RandomName::new(&name, None, &opts.naming);
         let (file, temp_path) = loop {
-            let path = dir_path.join(random_name.next());
+            let path = temp_dir_path.join(random_name.next());
             match File::options()
                 .write(true)
                 .read(opts.read)
@@ -59,27 +126,175 @@ impl TemporaryFile {
             {
                 Ok(file) => break (file, path),
                 Err(ref err) if err.kind() == ErrorKind::AlreadyExists => continue,
-                Err(err) => return Err(err),
+                Err(err) => return Err(err).at_stage(Stage::CreateTemporaryFile),
             }
         };
 
+        if let Err(err) = set_file_perms(&file, opts) {
+            // Best-effort: the error that caused the failure is more relevant
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+
         Ok(Self {
             temp_path,
-            dest_path,
+            dir_path: dir_path.to_owned(),
+            name,
             file,
+            renamed: false,
         })
     }
 
-    pub(crate) fn rename_file(&self) -> Result<()> {
-        fs::rename(&self.temp_path, &self.dest_path)
+    pub(crate) fn open_in(
+        _opts: &OpenOptions,
+        dir: Arc<Dir>,
+        _name: &OsStr,
+    ) -> error::Result<Self> {
+        match *dir {}
+    }
+
+    pub(crate) fn rename_file(&mut self) -> error::Result<()> {
+        fs::rename(&self.temp_path, self.dir_path.join(&self.name)).at_stage(Stage::Rename)?;
+        self.renamed = true;
+        Ok(())
+    }
+
+    /// Renames the temporary file to `name`, inside the subdirectory `subdir` of the destination
+    /// directory, unless `name` already exists. Returns `true` if `name` was created.
+    #[cfg_attr(not(feature = "store"), allow(dead_code))]
+    pub(crate) fn rename_file_no_replace(
+        &mut self,
+        subdir: &OsStr,
+        name: &OsStr,
+    ) -> error::Result<bool> {
+        let dir_path = self.dir_path.join(subdir);
+        match fs::create_dir(&dir_path) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err).at_stage(Stage::OpenDirectory),
+        }
+        // Unlike `fs::rename()`, `fs::hard_link()` fails if the destination already exists
+        match fs::hard_link(&self.temp_path, dir_path.join(name)) {
+            Ok(()) => {
+                // The contents are already at their final destination: failing to remove the
+                // temporary file only leaves a leftover behind
+                let _ = self.remove_file();
+                self.renamed = true;
+                Ok(true)
+            }
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {
+                self.remove_file()?;
+                Ok(false)
+            }
+            Err(err) => Err(err).at_stage(Stage::Rename),
+        }
+    }
+
+    pub(crate) fn remove_file(&self) -> error::Result<()> {
+        fs::remove_file(&self.temp_path).at_stage(Stage::RemoveTemporaryFile)
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> Option<&OsStr> {
+        if self.name.is_empty() {
+            None
+        } else {
+            Some(&self.name)
+        }
+    }
+
+    /// Changes the name that the temporary file will be renamed to.
+    pub(crate) fn set_name(&mut self, name: &OsStr) {
+        self.name = name.to_os_string();
+    }
+
+    /// Returns an error if `dir_path` is not the directory that will contain the destination file.
+    pub(crate) fn check_same_dir(&self, dir_path: &Path) -> error::Result<()> {
+        let canonicalize = |path: &Path| {
+            if path.as_os_str().is_empty() {
+                fs::canonicalize(".")
+            } else {
+                fs::canonicalize(path)
+            }
+        };
+        let expected = canonicalize(&self.dir_path).at_stage(Stage::Rename)?;
+        let actual = canonicalize(dir_path).at_stage(Stage::Rename)?;
+        if actual != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "destination is not in the same directory as the file",
+            ))
+            .at_stage(Stage::Rename);
+        }
+        Ok(())
+    }
+
+    /// Sets the mode that was deferred until commit time. Secret mode is not supported on this
+    /// platform, so this does nothing.
+    #[inline]
+    pub(crate) fn apply_final_mode(&self) -> error::Result<()> {
+        Ok(())
+    }
+
+    /// Returns an error if the destination directory is no longer at `dir_path`. Files are always
+    /// renamed by path on this platform, so the directory cannot change.
+    #[inline]
+    pub(crate) fn check_dir_unchanged(&self, _dir_path: &Path) -> error::Result<()> {
+        Ok(())
+    }
+
+    /// Opens the file that the temporary file will be renamed to for reading, if it exists.
+    /// Returns an error if it's not a regular file.
+    pub(crate) fn open_destination(&self) -> error::Result<Option<File>> {
+        let path = self.dir_path.join(&self.name);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => (),
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "destination is not a regular file",
+                ))
+                .at_stage(Stage::Diff);
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).at_stage(Stage::Diff),
+        }
+        match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).at_stage(Stage::Diff),
+        }
+    }
+
+    /// Overwrites the destination file with the contents of the temporary file, without renaming
+    /// it. Locking is not supported on this platform: `lock` is ignored.
+    pub(crate) fn write_in_place(&self, _lock: bool) -> error::Result<()> {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(self.dir_path.join(&self.name))
+            .and_then(|dest| copy_contents(&self.file, &dest))
+            .at_stage(Stage::WriteInPlace)
+    }
+
+    /// Returns a path that can be used to open the temporary file.
+    #[inline]
+    pub(crate) fn temporary_path(&self) -> error::Result<PathBuf> {
+        Ok(self.temp_path.clone())
+    }
+
+    #[inline]
+    pub(crate) fn temporary_name(&self) -> Option<&OsStr> {
+        self.temp_path.file_name()
     }
 
-    pub(crate) fn remove_file(&self) -> Result<()> {
-        fs::remove_file(&self.temp_path)
+    /// Returns `true` if the temporary file was already renamed to its destination.
+    #[inline]
+    pub(crate) fn is_renamed(&self) -> bool {
+        self.renamed
     }
 
     #[inline]
-    pub(crate) fn directory(&self) -> Option<&Dir> {
+    pub(crate) fn directory(&self) -> Option<&Arc<Dir>> {
         None
     }
 }
@@ -88,6 +303,15 @@ impl TemporaryFile {
 #[derive(Debug)]
 pub(crate) enum Dir {}
 
+impl Dir {
+    pub(crate) fn open<P: AsRef<Path>>(_path: P) -> Result<Self> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "directory handles are not supported on this platform",
+        ))
+    }
+}
+
 #[cfg(any(unix, target_os = "wasi"))]
 impl std::os::fd::AsFd for Dir {
     #[inline]
@@ -104,30 +328,105 @@ impl std::os::fd::AsRawFd for Dir {
     }
 }
 
-struct RandomName<'a> {
-    base_name: &'a OsStr,
+fn is_older_than(modified: SystemTime, max_age: Duration) -> bool {
+    match SystemTime::now().duration_since(modified) {
+        Ok(age) => age >= max_age,
+        // The modification time is in the future
+        Err(_) => false,
+    }
 }
 
-impl<'a> RandomName<'a> {
-    const SUFFIX_SIZE: usize = 6;
+/// Returns an error if the file at `path` exists but could not be replaced at commit time.
+fn check_destination(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if metadata.is_dir() {
+        Err(Error::new(
+            ErrorKind::IsADirectory,
+            "destination is a directory",
+        ))
+    } else if metadata.permissions().readonly() {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "destination is read-only",
+        ))
+    } else {
+        Ok(())
+    }
+}
 
-    fn new(base_name: &'a OsStr) -> Self {
-        Self { base_name }
+/// Sets the permissions of `file`, as requested by `opts`.
+fn set_file_perms(file: &File, opts: &OpenOptions) -> error::Result<()> {
+    if let Some(ref permissions) = opts.permissions {
+        file.set_permissions(permissions.clone())
+            .at_stage(Stage::SetPermissions)?;
+    } else if let Some(ref template) = opts.metadata_from {
+        let permissions = fs::metadata(template)
+            .at_stage(Stage::CopyPermissions)?
+            .permissions();
+        file.set_permissions(permissions)
+            .at_stage(Stage::CopyPermissions)?;
     }
+    Ok(())
+}
 
-    fn next(&mut self) -> OsString {
-        let mut rng = rand::rng();
-        let mut name = OsString::with_capacity(1 + self.base_name.len() + 1 + Self::SUFFIX_SIZE);
-        let mut suffix = Vec::with_capacity(Self::SUFFIX_SIZE);
-        name.push(".");
-        name.push(self.base_name);
-        name.push(".");
-        for _ in 0..Self::SUFFIX_SIZE {
-            suffix.push(rng.sample(Alphanumeric));
+pub(crate) fn remove_temporary_files(
+    dir_path: &Path,
+    base_name: Option<&OsStr>,
+    naming: &NameOptions,
+    max_age: Duration,
+) -> Result<Vec<OsString>> {
+    let dir_path = if !dir_path.as_os_str().is_empty() {
+        dir_path
+    } else {
+        Path::new(".")
+    };
+    let temp_dir_path = match naming.dir {
+        Some(ref temp_dir) => dir_path.join(temp_dir),
+        None => dir_path.to_owned(),
+    };
+    let mut removed = Vec::new();
+
+    for entry in fs::read_dir(&temp_dir_path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let parsed = match RandomName::parse(&name, base_name, naming) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        // Without a base name, only temporary files that can be positively identified are
+        // removed (see the Unix implementation)
+        if base_name.is_none() && parsed.owner.is_none() {
+            match fs::symlink_metadata(dir_path.join(parsed.base_name)) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        // The entry may have been committed or removed by its owner since it was listed
+        let path = entry.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        if !is_older_than(metadata.modified()?, max_age) {
+            continue;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(name),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
         }
-        // SAFETY: `suffix` contains only ASCII alphanumeric characters, which are valid utf-8
-        // characters
-        name.push(unsafe { String::from_utf8_unchecked(suffix) });
-        name
     }
+
+    Ok(removed)
 }
@@ -1,3 +1,11 @@
+mod name;
+
+pub(crate) use self::name::*;
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+
 #[cfg(unix)]
 pub(crate) mod unix;
 
@@ -10,5 +18,12 @@ pub(crate) mod generic;
 #[cfg(not(unix))]
 pub(crate) use self::generic::*;
 
-// TODO On Windows, use CreateFileW with FILE_ATTRIBUTE_HIDDEN, FILE_FLAG_DELETE_ON_CLOSE +
-// MoveFileEx with MOVEFILE_REPLACE_EXISTING, MOVEFILE_WRITE_THROUGH
+/// Overwrites the contents of `dest` with the contents of `src`, and flushes them to the storage
+/// device. The contents are written before `dest` is truncated, so that `dest` is never empty
+/// (unless `src` is).
+pub(crate) fn copy_contents(mut src: &File, mut dest: &File) -> io::Result<()> {
+    src.seek(SeekFrom::Start(0))?;
+    let len = io::copy(&mut src, &mut dest)?;
+    dest.set_len(len)?;
+    dest.sync_all()
+}
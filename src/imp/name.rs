@@ -0,0 +1,241 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::distr::Alphanumeric;
+use rand::rngs::StdRng;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::io::Write;
+use std::path::PathBuf;
+use std::path::is_separator;
+
+/// Maximum length of a file name, in bytes, on most platforms and filesystems.
+const NAME_MAX: usize = 255;
+
+/// Options that control how temporary files are named, and where they are placed.
+#[derive(Clone, Debug)]
+pub(crate) struct NameOptions {
+    pub(crate) prefix: OsString,
+    pub(crate) suffix: OsString,
+    pub(crate) random_len: usize,
+    pub(crate) seed: Option<u64>,
+    pub(crate) dir: Option<PathBuf>,
+}
+
+impl NameOptions {
+    pub(crate) fn new() -> Self {
+        Self {
+            prefix: OsString::from("."),
+            suffix: OsString::new(),
+            random_len: 6,
+            seed: None,
+            dir: None,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.random_len == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "length of the random part of temporary file names must be greater than zero",
+            ));
+        }
+        for part in [&self.prefix, &self.suffix] {
+            if part.to_string_lossy().chars().any(is_separator) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "prefix and suffix of temporary file names cannot contain path separators",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for NameOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Information about the process that created a temporary file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Owner {
+    /// Process ID of the writer.
+    pub(crate) pid: u32,
+    /// Time when the temporary file was created, in seconds since boot.
+    pub(crate) timestamp: u64,
+}
+
+/// Information extracted from a temporary file name by [`RandomName::parse()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ParsedName<'a> {
+    /// The name of the destination file, possibly truncated.
+    pub(crate) base_name: &'a OsStr,
+    pub(crate) owner: Option<Owner>,
+}
+
+/// Generator for temporary file names.
+///
+/// Names are in the form `{prefix}{base_name}.{XXXXXX}{suffix}`, where `XXXXXX` is a random
+/// alphanumeric string. If an [`Owner`] is specified, names are in the form
+/// `{prefix}{base_name}.{XXXXXX}.{pid}-{timestamp}{suffix}`. If the resulting name would be longer
+/// than `NAME_MAX`, `base_name` is truncated.
+pub(crate) struct RandomName {
+    buf: Vec<u8>,
+    random_start: usize,
+    random_len: usize,
+    rng: StdRng,
+}
+
+impl RandomName {
+    pub(crate) fn new(base_name: &OsStr, owner: Option<Owner>, opts: &NameOptions) -> Self {
+        let prefix = opts.prefix.as_encoded_bytes();
+        let suffix = opts.suffix.as_encoded_bytes();
+        let mut owner_part = Vec::new();
+        if let Some(owner) = owner {
+            write!(owner_part, ".{}-{}", owner.pid, owner.timestamp).expect("write to Vec failed");
+        }
+
+        let fixed_len = prefix.len() + 1 + opts.random_len + owner_part.len() + suffix.len();
+        let base_name = truncate(base_name, NAME_MAX.saturating_sub(fixed_len));
+
+        let mut buf = Vec::with_capacity(fixed_len + base_name.len());
+        buf.extend_from_slice(prefix);
+        buf.extend_from_slice(base_name);
+        buf.push(b'.');
+        let random_start = buf.len();
+        buf.resize(random_start + opts.random_len, b'0');
+        buf.extend_from_slice(&owner_part);
+        buf.extend_from_slice(suffix);
+
+        let rng = match opts.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+
+        Self {
+            buf,
+            random_start,
+            random_len: opts.random_len,
+            rng,
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> &OsStr {
+        let random = &mut self.buf[self.random_start..self.random_start + self.random_len];
+        for c in random.iter_mut() {
+            *c = self.rng.sample(Alphanumeric);
+        }
+        // SAFETY: `buf` is the concatenation of the encoded bytes of valid `OsStr`s (split only at
+        // sequence boundaries, see `truncate()`) and ASCII characters
+        unsafe { OsStr::from_encoded_bytes_unchecked(&self.buf) }
+    }
+
+    #[inline]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn into_os_string(self) -> OsString {
+        // SAFETY: `buf` is the concatenation of the encoded bytes of valid `OsStr`s (split only at
+        // sequence boundaries, see `truncate()`) and ASCII characters
+        unsafe { OsString::from_encoded_bytes_unchecked(self.buf) }
+    }
+
+    /// Checks whether `name` could have been generated by a `RandomName` for `base_name` (or for
+    /// any base name, if `base_name` is `None`), and if so returns the information encoded in it.
+    pub(crate) fn parse<'a>(
+        name: &'a OsStr,
+        base_name: Option<&OsStr>,
+        opts: &NameOptions,
+    ) -> Option<ParsedName<'a>> {
+        let name_len = name.len();
+        let mut name = name
+            .as_encoded_bytes()
+            .strip_prefix(opts.prefix.as_encoded_bytes())?
+            .strip_suffix(opts.suffix.as_encoded_bytes())?;
+
+        let owner = match parse_owner(name) {
+            Some((rest, owner)) => {
+                name = rest;
+                Some(owner)
+            }
+            None => None,
+        };
+
+        let random_start = name.len().checked_sub(opts.random_len)?;
+        let (head, random) = name.split_at(random_start);
+        if !random.iter().all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+        let middle = head.strip_suffix(b".")?;
+        let parsed = ParsedName {
+            // SAFETY: `middle` was obtained from the encoded bytes of a valid `OsStr` by removing
+            // a valid `OsStr` prefix and suffix, and ASCII characters
+            base_name: unsafe { OsStr::from_encoded_bytes_unchecked(middle) },
+            owner,
+        };
+
+        let base_name = match base_name {
+            Some(base_name) => base_name.as_encoded_bytes(),
+            None => return Some(parsed),
+        };
+        // The base name may have been truncated to fit `NAME_MAX`; in that case, `truncate()` may
+        // have removed up to 3 more bytes to avoid splitting a UTF-8 character
+        let truncated = name_len + 3 >= NAME_MAX
+            && middle.len() < base_name.len()
+            && base_name.starts_with(middle);
+        if middle == base_name || truncated {
+            Some(parsed)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for RandomName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RandomName")
+            .field("buf", &String::from_utf8_lossy(&self.buf))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Truncates `name` so that it is at most `max_len` bytes long.
+///
+/// The name is cut at the end of a UTF-8 (or WTF-8, on Windows) sequence, so that the result can be
+/// safely concatenated with other encoded bytes. On Unix, names that are not valid UTF-8 may be cut
+/// at any byte, as they are opaque byte strings.
+fn truncate(name: &OsStr, max_len: usize) -> &[u8] {
+    let bytes = name.as_encoded_bytes();
+    if bytes.len() <= max_len {
+        return bytes;
+    }
+    // A sequence is at most 4 bytes long, so at most 3 continuation bytes (`0b10xxxxxx`) need to
+    // be dropped to reach the start of a sequence. Only on Unix the encoded bytes may be malformed,
+    // and there stopping after 3 bytes is fine even if no sequence start was found
+    let mut end = max_len;
+    while end > 0 && max_len - end < 3 && bytes[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    &bytes[..end]
+}
+
+/// Splits a trailing `.{pid}-{timestamp}` from `name`.
+fn parse_owner(name: &[u8]) -> Option<(&[u8], Owner)> {
+    let dot = name.iter().rposition(|&c| c == b'.')?;
+    let (rest, owner) = (&name[..dot], &name[dot + 1..]);
+    let dash = owner.iter().position(|&c| c == b'-')?;
+    let pid = parse_number(&owner[..dash])?;
+    let timestamp = parse_number(&owner[dash + 1..])?;
+    let pid = u32::try_from(pid).ok()?;
+    Some((rest, Owner { pid, timestamp }))
+}
+
+fn parse_number(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
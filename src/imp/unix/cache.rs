@@ -0,0 +1,89 @@
+//! Process-wide cache of directory handles, shared between temporary files in the same directory.
+//!
+//! Entries are keyed by device and inode number, so that a cached handle is only reused if the
+//! path still refers to the same directory: if the directory is renamed or replaced, `stat(2)`
+//! returns a different key, and a new handle is opened. The cache only holds weak references:
+//! directories are closed as soon as the last temporary file using them is dropped.
+
+use crate::imp::unix::Dir;
+use nix::sys::stat::fstat;
+use nix::sys::stat::stat;
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+type Key = (u64, u64);
+
+#[derive(Debug)]
+struct Cache {
+    entries: HashMap<Key, Weak<Dir>>,
+    /// Size at which dead entries are pruned next.
+    prune_at: usize,
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+impl Cache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            prune_at: 16,
+        }
+    }
+
+    fn get(&self, key: Key) -> Option<Arc<Dir>> {
+        self.entries.get(&key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: Key, dir: &Arc<Dir>) {
+        self.entries.insert(key, Arc::downgrade(dir));
+        if self.entries.len() >= self.prune_at {
+            self.entries.retain(|_, dir| dir.strong_count() > 0);
+            self.prune_at = (self.entries.len() * 2).max(16);
+        }
+    }
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn key(stat: &nix::sys::stat::FileStat) -> Key {
+    (stat.st_dev as u64, stat.st_ino as u64)
+}
+
+/// Returns a handle to the directory at `path`, reusing a cached handle if one exists.
+pub(crate) fn open_dir(path: &Path) -> Result<Arc<Dir>> {
+    let wanted = key(&stat(path)?);
+    if let Some(dir) = CACHE
+        .lock()
+        .expect("directory cache poisoned")
+        .as_ref()
+        .and_then(|cache| cache.get(wanted))
+    {
+        return Ok(dir);
+    }
+
+    // The directory may have been replaced since `stat()` was called, so use the key of the
+    // directory that was actually opened
+    share(Dir::open(path)?)
+}
+
+/// Adds the already opened directory `dir` to the cache, or returns the cached handle for the same
+/// directory if one exists (in which case `dir` is closed).
+pub(crate) fn share(dir: Dir) -> Result<Arc<Dir>> {
+    let actual = key(&fstat(&dir)?);
+    let dir = Arc::new(dir);
+
+    let mut cache = CACHE.lock().expect("directory cache poisoned");
+    let cache = cache.get_or_insert_with(Cache::new);
+    // Another thread may have opened the same directory concurrently; if so, prefer its handle so
+    // that only one stays open
+    match cache.get(actual) {
+        Some(existing) => Ok(existing),
+        None => {
+            cache.insert(actual, &dir);
+            Ok(dir)
+        }
+    }
+}
@@ -1,60 +1,259 @@
+use crate::error::Result;
+use crate::error::ResultExt;
+use crate::error::Stage;
 use crate::imp::unix::Dir;
 use crate::imp::unix::OpenOptions;
-use crate::imp::unix::copy_file_perms;
+use crate::imp::unix::check_dir_unchanged;
+#[cfg(feature = "cap-std")]
+use crate::imp::unix::check_dir_unchanged_at;
+use crate::imp::unix::check_dirs;
+use crate::imp::unix::check_same_dir;
 use crate::imp::unix::create_temporary_file;
+use crate::imp::unix::fd_path;
+use crate::imp::unix::open_destination;
+use crate::imp::unix::open_dir;
+use crate::imp::unix::open_or_create_subdir;
+use crate::imp::unix::open_parent_dir;
+use crate::imp::unix::open_temporary_dir;
+use crate::imp::unix::registry;
+use crate::imp::unix::registry::Registration;
+use crate::imp::unix::remove_stale_temporary_files;
 use crate::imp::unix::remove_temporary_file;
 use crate::imp::unix::rename_temporary_file;
-use nix::errno::Errno;
+use crate::imp::unix::rename_temporary_file_no_replace;
+use crate::imp::unix::scrub_file;
+use crate::imp::unix::set_file_perms;
+use crate::imp::unix::sync_renamed;
+use crate::imp::unix::write_in_place;
+use nix::sys::stat::Mode;
+use nix::sys::stat::fchmod;
+use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::Result;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub(crate) struct TemporaryFile {
-    pub(crate) dir: Dir,
+    // Must be dropped before `dir` and `temporary_dir` are closed
+    registration: Option<Registration>,
+    pub(crate) dir: Arc<Dir>,
+    pub(crate) temporary_dir: Option<Dir>,
     pub(crate) file: File,
+    /// In secret mode, the mode to set on the file at commit time. The contents of the file are
+    /// also overwritten when it's removed.
+    final_mode: Option<Mode>,
+    /// Parents of the directories created when the file was opened, to sync at commit time.
+    pub(crate) unsynced_parents: Vec<Dir>,
+    /// Whether the temporary file was renamed (or linked) to its destination. After that, `file`
+    /// refers to the destination file, and the temporary file must no longer be removed.
+    renamed: bool,
     pub(crate) name: OsString,
     pub(crate) temporary_name: OsString,
 }
 
 impl TemporaryFile {
     pub(crate) fn open(opts: &OpenOptions, path: &Path) -> Result<Self> {
-        let dir_path = path.parent().ok_or(Errno::EISDIR)?;
-        let name = path.file_name().ok_or(Errno::EISDIR)?.to_os_string();
+        let (dir, name, unsynced_parents) = open_parent_dir(path, opts)?;
+        let mut file = Self::open_in(opts, dir, name)?;
+        file.unsynced_parents = unsynced_parents;
+        Ok(file)
+    }
 
-        let dir = if !dir_path.as_os_str().is_empty() {
-            Dir::open(dir_path)?
-        } else {
-            Dir::open(".")?
-        };
+    /// Opens a temporary file in `dir_path`, without a destination name. The name must be set with
+    /// [`set_name()`](Self::set_name) before the file can be renamed.
+    pub(crate) fn open_unnamed(opts: &OpenOptions, dir_path: &Path) -> Result<Self> {
+        let (dir, unsynced_parents) = open_dir(dir_path, opts)?;
+        let mut file = Self::open_in(opts, dir, OsStr::new(""))?;
+        file.unsynced_parents = unsynced_parents;
+        Ok(file)
+    }
 
-        let (file, temporary_name) = create_temporary_file(&dir, opts, &name)?;
+    pub(crate) fn open_in(opts: &OpenOptions, dir: Arc<Dir>, name: &OsStr) -> Result<Self> {
+        opts.naming
+            .validate()
+            .at_stage(Stage::CreateTemporaryFile)?;
+        let temporary_dir =
+            open_temporary_dir(&dir, opts).at_stage(Stage::OpenTemporaryDirectory)?;
+        Self::open_with_dirs(opts, dir, temporary_dir, name)
+    }
 
-        if opts.preserve_mode || opts.preserve_owner.is_yes() {
-            copy_file_perms(&dir, &name, &file, opts)?;
-        }
+    /// Like [`open_in()`](Self::open_in), but with the directory for temporary files already
+    /// opened by the caller. `opts.naming` must have been validated by the caller.
+    pub(crate) fn open_with_dirs(
+        opts: &OpenOptions,
+        dir: Arc<Dir>,
+        temporary_dir: Option<Dir>,
+        name: &OsStr,
+    ) -> Result<Self> {
+        check_dirs(opts, &dir, temporary_dir.as_ref(), name)?;
+
+        let name = name.to_os_string();
+        remove_stale_temporary_files(&dir, temporary_dir.as_ref(), &name, opts);
+
+        let (file, temporary_name) =
+            create_temporary_file(temporary_dir.as_ref().unwrap_or(&dir), opts, &name)
+                .at_stage(Stage::CreateTemporaryFile)?;
+
+        let final_mode = match set_file_perms(&dir, &name, &file, opts) {
+            Ok(final_mode) => final_mode,
+            Err(err) => {
+                // Best-effort: the error that caused the failure is more relevant
+                let _ =
+                    remove_temporary_file(temporary_dir.as_ref().unwrap_or(&dir), &temporary_name);
+                return Err(err);
+            }
+        };
+
+        let registration =
+            registry::register(temporary_dir.as_ref().unwrap_or(&dir), &temporary_name);
 
         Ok(Self {
+            registration,
             dir,
+            temporary_dir,
             file,
+            final_mode,
+            unsynced_parents: Vec::new(),
+            renamed: false,
             name,
             temporary_name,
         })
     }
 
-    pub(crate) fn rename_file(&self) -> Result<()> {
-        rename_temporary_file(&self.dir, &self.temporary_name, &self.name)?;
-        Ok(())
+    #[inline]
+    fn temporary_dir(&self) -> &Dir {
+        self.temporary_dir.as_ref().unwrap_or(&self.dir)
     }
 
-    pub(crate) fn remove_file(&self) -> Result<()> {
-        remove_temporary_file(&self.dir, &self.temporary_name)?;
-        Ok(())
+    pub(crate) fn rename_file(&mut self) -> Result<()> {
+        rename_temporary_file(
+            self.temporary_dir(),
+            &self.temporary_name,
+            &self.dir,
+            &self.name,
+        )?;
+        self.renamed = true;
+        self.registration = None;
+        sync_renamed(&self.dir, &mut self.unsynced_parents)
+    }
+
+    /// Renames the temporary file to `name`, inside the subdirectory `subdir` of the destination
+    /// directory, unless `name` already exists. Returns `true` if `name` was created.
+    #[cfg_attr(not(feature = "store"), allow(dead_code))]
+    pub(crate) fn rename_file_no_replace(&mut self, subdir: &OsStr, name: &OsStr) -> Result<bool> {
+        let dir = open_or_create_subdir(&self.dir, subdir).at_stage(Stage::OpenDirectory)?;
+        let created = rename_temporary_file_no_replace(
+            self.temporary_dir(),
+            &self.temporary_name,
+            &dir,
+            name,
+        )?;
+        self.renamed = true;
+        self.registration = None;
+        sync_renamed(&dir, &mut self.unsynced_parents)?;
+        Ok(created)
+    }
+
+    /// Sets the mode that was deferred until commit time in secret mode.
+    pub(crate) fn apply_final_mode(&self) -> Result<()> {
+        match self.final_mode {
+            Some(mode) => fchmod(&self.file, mode).at_stage(Stage::SetPermissions),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the permission bits that will be set on the file at commit time, if they were
+    /// deferred in secret mode.
+    pub(crate) fn final_mode(&self) -> Option<u32> {
+        // See `set_file_perms()` for why the cast is needed
+        #[allow(clippy::unnecessary_cast)]
+        let mode = self.final_mode.map(|mode| mode.bits() as u32 & 0o7777);
+        mode
+    }
+
+    pub(crate) fn remove_file(&mut self) -> Result<()> {
+        if self.renamed {
+            // `file` is now the destination file: it must be neither overwritten nor removed
+            return Ok(());
+        }
+        // In secret mode, the contents are overwritten first, so that they do not linger on the
+        // storage device. The file is removed even if that fails.
+        let scrubbed = match self.final_mode {
+            Some(_) => scrub_file(&self.file).at_stage(Stage::RemoveTemporaryFile),
+            None => Ok(()),
+        };
+        remove_temporary_file(self.temporary_dir(), &self.temporary_name)
+            .at_stage(Stage::RemoveTemporaryFile)?;
+        self.registration = None;
+        scrubbed
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> Option<&OsStr> {
+        if self.name.is_empty() {
+            None
+        } else {
+            Some(&self.name)
+        }
+    }
+
+    /// Changes the name that the temporary file will be renamed to.
+    pub(crate) fn set_name(&mut self, name: &OsStr) {
+        self.name = name.to_os_string();
+    }
+
+    /// Returns an error if `dir_path` is not the directory that will contain the destination file.
+    pub(crate) fn check_same_dir(&self, dir_path: &Path) -> Result<()> {
+        check_same_dir(&self.dir, dir_path).at_stage(Stage::Rename)
+    }
+
+    /// Returns an error if the destination directory is no longer at `dir_path`.
+    pub(crate) fn check_dir_unchanged(&self, dir_path: &Path) -> Result<()> {
+        check_dir_unchanged(&self.dir, dir_path).at_stage(Stage::CheckDirectory)
+    }
+
+    /// Returns an error if the destination directory is no longer at `dir_path`, relative to
+    /// `base`.
+    #[cfg(feature = "cap-std")]
+    pub(crate) fn check_dir_unchanged_at(
+        &self,
+        base: &cap_std::fs::Dir,
+        dir_path: &Path,
+    ) -> Result<()> {
+        check_dir_unchanged_at(&self.dir, base, dir_path).at_stage(Stage::CheckDirectory)
+    }
+
+    /// Opens the file that the temporary file will be renamed to for reading, if it exists.
+    pub(crate) fn open_destination(&self) -> Result<Option<File>> {
+        open_destination(&self.dir, &self.name).at_stage(Stage::Diff)
+    }
+
+    /// Overwrites the destination file with the contents of the temporary file, without renaming
+    /// it. If `lock` is `true`, the destination file is locked while it's written.
+    pub(crate) fn write_in_place(&self, lock: bool) -> Result<()> {
+        write_in_place(&self.file, &self.dir, &self.name, lock).at_stage(Stage::WriteInPlace)
+    }
+
+    /// Returns a path that can be used to open the temporary file.
+    pub(crate) fn temporary_path(&self) -> Result<PathBuf> {
+        fd_path(&self.file).at_stage(Stage::Validate)
+    }
+
+    #[inline]
+    pub(crate) fn temporary_name(&self) -> Option<&OsStr> {
+        Some(&self.temporary_name)
+    }
+
+    /// Returns `true` if the temporary file was already renamed to its destination.
+    #[inline]
+    pub(crate) fn is_renamed(&self) -> bool {
+        self.renamed
     }
 
     #[inline]
-    pub(crate) fn directory(&self) -> Option<&Dir> {
+    pub(crate) fn directory(&self) -> Option<&Arc<Dir>> {
         Some(&self.dir)
     }
 }
@@ -1,24 +1,49 @@
+use crate::error::Result;
+use crate::error::ResultExt;
+use crate::error::Stage;
+use crate::imp::RandomName;
 use crate::imp::unix::Dir;
 use crate::imp::unix::OpenOptions;
-use crate::imp::unix::RandomName;
-use crate::imp::unix::copy_file_perms;
+use crate::imp::unix::check_dir_unchanged;
+#[cfg(feature = "cap-std")]
+use crate::imp::unix::check_dir_unchanged_at;
+use crate::imp::unix::check_dirs;
+use crate::imp::unix::check_same_dir;
+use crate::imp::unix::create_mode;
 use crate::imp::unix::create_temporary_file;
+use crate::imp::unix::fd_path;
+use crate::imp::unix::open_destination;
+use crate::imp::unix::open_dir;
+use crate::imp::unix::open_or_create_subdir;
+use crate::imp::unix::open_parent_dir;
+use crate::imp::unix::open_temporary_dir;
+use crate::imp::unix::registry;
+use crate::imp::unix::registry::Registration;
+use crate::imp::unix::remove_stale_temporary_files;
 use crate::imp::unix::remove_temporary_file;
 use crate::imp::unix::rename_temporary_file;
+use crate::imp::unix::rename_temporary_file_no_replace;
+use crate::imp::unix::scrub_file;
+use crate::imp::unix::set_file_perms;
+use crate::imp::unix::sync_renamed;
+use crate::imp::unix::temporary_name_owner;
+use crate::imp::unix::write_in_place;
 use nix::errno::Errno;
 use nix::fcntl::AtFlags;
 use nix::fcntl::OFlag;
 use nix::fcntl::openat;
 use nix::libc;
 use nix::sys::stat::Mode;
+use nix::sys::stat::fchmod;
 use nix::unistd::fdatasync;
 use nix::unistd::linkat;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::Result;
 use std::os::fd::AsRawFd;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 fn create_unnamed_temporary_file(dir: &Dir, opts: &OpenOptions) -> nix::Result<File> {
     let access_mode = if opts.read {
@@ -30,106 +55,313 @@ fn create_unnamed_temporary_file(dir: &Dir, opts: &OpenOptions) -> nix::Result<F
         | access_mode
         | OFlag::O_CLOEXEC
         | OFlag::from_bits_truncate(opts.custom_flags & !libc::O_ACCMODE);
-    let create_mode = Mode::from_bits_truncate(opts.mode);
+    let create_mode = create_mode(opts);
 
     openat(dir, ".", flags, create_mode).map(File::from)
 }
 
-fn rename_unnamed_temporary_file(dir: &Dir, file: &File, name: &OsStr) -> nix::Result<()> {
+/// Creates a link to the unnamed temporary `file` at `name`, inside `dir`.
+fn link_unnamed_file(file: &File, dir: &Dir, name: &OsStr) -> nix::Result<()> {
     let fd = file.as_raw_fd();
     let src = OsString::from(format!("/proc/self/fd/{fd}"));
-    let mut random_name = RandomName::new(name);
+    linkat(dir, src.as_os_str(), dir, name, AtFlags::AT_SYMLINK_FOLLOW)
+}
 
-    let temporary_name = loop {
-        match linkat(
-            dir,
-            src.as_os_str(),
-            dir,
-            random_name.next(),
-            AtFlags::AT_SYMLINK_FOLLOW,
-        ) {
-            Ok(()) => break random_name.into_os_string(),
+/// Gives a name to the unnamed temporary `file`, returning the new name.
+fn link_unnamed_temporary_file(
+    temporary_dir: &Dir,
+    file: &File,
+    random_name: &mut RandomName,
+) -> Result<OsString> {
+    loop {
+        let temporary_name = random_name.next();
+        match link_unnamed_file(file, temporary_dir, temporary_name) {
+            Ok(()) => return Ok(temporary_name.to_os_string()),
             Err(Errno::EEXIST) => continue,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err).at_stage(Stage::Link),
         }
-    };
-
-    // The file is supposed to be already synced by `AtomicWriteFile::_commit()`, however on some
-    // filesystems (notably, btrfs), syncs are ignored for unnamed temporary files, hence we sync
-    // again for safety.
-    //
-    // See https://github.com/andreacorbellini/rust-atomic-write-file/issues/6 for more details.
-    fdatasync(file)?;
-
-    rename_temporary_file(dir, &temporary_name, name)
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct TemporaryFile {
-    pub(crate) dir: Dir,
+    // Must be dropped before `dir` and `temporary_dir` are closed
+    registration: Option<Registration>,
+    pub(crate) dir: Arc<Dir>,
+    pub(crate) temporary_dir: Option<Dir>,
     pub(crate) file: File,
+    /// In secret mode, the mode to set on the file at commit time. The contents of the file are
+    /// also overwritten when it's removed.
+    final_mode: Option<Mode>,
+    /// Parents of the directories created when the file was opened, to sync at commit time.
+    pub(crate) unsynced_parents: Vec<Dir>,
+    /// Whether the temporary file was renamed (or linked) to its destination. After that, `file`
+    /// refers to the destination file, and the temporary file must no longer be removed.
+    renamed: bool,
     pub(crate) name: OsString,
     pub(crate) temporary_name: Option<OsString>,
+    random_name: RandomName,
 }
 
 impl TemporaryFile {
     pub(crate) fn open(opts: &OpenOptions, path: &Path) -> Result<Self> {
-        let dir_path = path.parent().ok_or(Errno::EISDIR)?;
-        let name = path.file_name().ok_or(Errno::EISDIR)?.to_os_string();
+        let (dir, name, unsynced_parents) = open_parent_dir(path, opts)?;
+        let mut file = Self::open_in(opts, dir, name)?;
+        file.unsynced_parents = unsynced_parents;
+        Ok(file)
+    }
 
-        let dir = if !dir_path.as_os_str().is_empty() {
-            Dir::open(dir_path)?
-        } else {
-            Dir::open(".")?
-        };
+    /// Opens a temporary file in `dir_path`, without a destination name. The name must be set with
+    /// [`set_name()`](Self::set_name) before the file can be renamed.
+    pub(crate) fn open_unnamed(opts: &OpenOptions, dir_path: &Path) -> Result<Self> {
+        let (dir, unsynced_parents) = open_dir(dir_path, opts)?;
+        let mut file = Self::open_in(opts, dir, OsStr::new(""))?;
+        file.unsynced_parents = unsynced_parents;
+        Ok(file)
+    }
+
+    pub(crate) fn open_in(opts: &OpenOptions, dir: Arc<Dir>, name: &OsStr) -> Result<Self> {
+        opts.naming
+            .validate()
+            .at_stage(Stage::CreateTemporaryFile)?;
+        let temporary_dir =
+            open_temporary_dir(&dir, opts).at_stage(Stage::OpenTemporaryDirectory)?;
+        Self::open_with_dirs(opts, dir, temporary_dir, name)
+    }
+
+    /// Like [`open_in()`](Self::open_in), but with the directory for temporary files already
+    /// opened by the caller. `opts.naming` must have been validated by the caller.
+    pub(crate) fn open_with_dirs(
+        opts: &OpenOptions,
+        dir: Arc<Dir>,
+        temporary_dir: Option<Dir>,
+        name: &OsStr,
+    ) -> Result<Self> {
+        check_dirs(opts, &dir, temporary_dir.as_ref(), name)?;
+
+        let name = name.to_os_string();
+        remove_stale_temporary_files(&dir, temporary_dir.as_ref(), &name, opts);
+
+        // Unnamed temporary files are given a name when they're committed: use the same owner as
+        // named temporary files, so that they can be told apart from leftovers in the same way
+        let owner = temporary_name_owner(opts).at_stage(Stage::CreateTemporaryFile)?;
 
         // Try to open an unnamed temporary file (with O_TMPFILE). This may not be supported on all
         // filesystems; if it's not supported, create a named temporary file in the same way the
         // generic Unix implementation would do.
-        let (file, temporary_name) = match create_unnamed_temporary_file(&dir, opts) {
-            Ok(file) => (file, None),
-            // Linux >= 3.11 may return ENOTSUP if the filesystem does not support unnamed
-            // temporary files; Linux < 3.11 will return EISDIR because O_TMPFILE is not supported
-            // at all.
-            Err(Errno::ENOTSUP) | Err(Errno::EISDIR) => {
-                let (file, temporary_name) = create_temporary_file(&dir, opts, &name)?;
-                (file, Some(temporary_name))
+        let (file, temporary_name) =
+            match create_unnamed_temporary_file(temporary_dir.as_ref().unwrap_or(&dir), opts) {
+                Ok(file) => (file, None),
+                // Linux >= 3.11 may return ENOTSUP if the filesystem does not support unnamed
+                // temporary files; Linux < 3.11 will return EISDIR because O_TMPFILE is not
+                // supported at all.
+                Err(Errno::ENOTSUP) | Err(Errno::EISDIR) => {
+                    let (file, temporary_name) =
+                        create_temporary_file(temporary_dir.as_ref().unwrap_or(&dir), opts, &name)
+                            .at_stage(Stage::CreateTemporaryFile)?;
+                    (file, Some(temporary_name))
+                }
+                Err(err) => return Err(err).at_stage(Stage::CreateTemporaryFile),
+            };
+
+        let final_mode = match set_file_perms(&dir, &name, &file, opts) {
+            Ok(final_mode) => final_mode,
+            Err(err) => {
+                if let Some(ref temporary_name) = temporary_name {
+                    // Best-effort: the error that caused the failure is more relevant
+                    let _ = remove_temporary_file(
+                        temporary_dir.as_ref().unwrap_or(&dir),
+                        temporary_name,
+                    );
+                }
+                return Err(err);
             }
-            Err(err) => return Err(err.into()),
         };
 
-        if opts.preserve_mode || opts.preserve_owner.is_yes() {
-            copy_file_perms(&dir, &name, &file, opts)?;
-        }
+        let random_name = RandomName::new(&name, owner, &opts.naming);
+
+        // Unnamed temporary files are removed automatically by the kernel, so only named ones
+        // need to be registered
+        let registration = temporary_name.as_ref().and_then(|temporary_name| {
+            registry::register(temporary_dir.as_ref().unwrap_or(&dir), temporary_name)
+        });
 
         Ok(Self {
+            registration,
             dir,
+            temporary_dir,
             file,
+            final_mode,
+            unsynced_parents: Vec::new(),
+            renamed: false,
             name,
             temporary_name,
+            random_name,
         })
     }
 
-    pub(crate) fn rename_file(&self) -> Result<()> {
-        match self.temporary_name {
-            None => rename_unnamed_temporary_file(&self.dir, &self.file, &self.name)?,
+    #[inline]
+    fn temporary_dir(&self) -> &Dir {
+        self.temporary_dir.as_ref().unwrap_or(&self.dir)
+    }
+
+    pub(crate) fn rename_file(&mut self) -> Result<()> {
+        let temporary_dir = self.temporary_dir.as_ref().unwrap_or(&self.dir);
+        let temporary_name = match self.temporary_name {
+            Some(ref temporary_name) => temporary_name,
+            None => {
+                let temporary_name =
+                    link_unnamed_temporary_file(temporary_dir, &self.file, &mut self.random_name)?;
+                // From now on, the temporary file has a name, and needs to be removed explicitly
+                // if the commit fails
+                self.registration = registry::register(temporary_dir, &temporary_name);
+                let temporary_name = self.temporary_name.insert(temporary_name);
+
+                // The file is supposed to be already synced by `AtomicWriteFile::_commit()`,
+                // however on some filesystems (notably, btrfs), syncs are ignored for unnamed
+                // temporary files, hence we sync again for safety.
+                //
+                // See https://github.com/andreacorbellini/rust-atomic-write-file/issues/6 for
+                // more details.
+                fdatasync(&self.file).at_stage(Stage::Sync)?;
+
+                temporary_name
+            }
+        };
+        rename_temporary_file(temporary_dir, temporary_name, &self.dir, &self.name)?;
+        self.renamed = true;
+        self.registration = None;
+        sync_renamed(&self.dir, &mut self.unsynced_parents)
+    }
+
+    /// Renames the temporary file to `name`, inside the subdirectory `subdir` of the destination
+    /// directory, unless `name` already exists. Returns `true` if `name` was created.
+    #[cfg_attr(not(feature = "store"), allow(dead_code))]
+    pub(crate) fn rename_file_no_replace(&mut self, subdir: &OsStr, name: &OsStr) -> Result<bool> {
+        let dir = open_or_create_subdir(&self.dir, subdir).at_stage(Stage::OpenDirectory)?;
+        let created = match self.temporary_name {
             Some(ref temporary_name) => {
-                rename_temporary_file(&self.dir, temporary_name, &self.name)?
+                rename_temporary_file_no_replace(self.temporary_dir(), temporary_name, &dir, name)?
+            }
+            None => {
+                // See the comment in `rename_file()`
+                fdatasync(&self.file).at_stage(Stage::Sync)?;
+                // Linking fails if `name` already exists, so there is no need for a temporary name
+                match link_unnamed_file(&self.file, &dir, name) {
+                    Ok(()) => true,
+                    Err(Errno::EEXIST) => false,
+                    Err(err) => return Err(err).at_stage(Stage::Link),
+                }
             }
+        };
+        self.renamed = true;
+        self.registration = None;
+        sync_renamed(&dir, &mut self.unsynced_parents)?;
+        Ok(created)
+    }
+
+    /// Sets the mode that was deferred until commit time in secret mode.
+    pub(crate) fn apply_final_mode(&self) -> Result<()> {
+        match self.final_mode {
+            Some(mode) => fchmod(&self.file, mode).at_stage(Stage::SetPermissions),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    pub(crate) fn remove_file(&self) -> Result<()> {
+    /// Returns the permission bits that will be set on the file at commit time, if they were
+    /// deferred in secret mode.
+    pub(crate) fn final_mode(&self) -> Option<u32> {
+        // See `set_file_perms()` for why the cast is needed
+        #[allow(clippy::unnecessary_cast)]
+        let mode = self.final_mode.map(|mode| mode.bits() as u32 & 0o7777);
+        mode
+    }
+
+    pub(crate) fn remove_file(&mut self) -> Result<()> {
+        if self.renamed {
+            // `file` is now the destination file: it must be neither overwritten nor removed
+            return Ok(());
+        }
+        // In secret mode, the contents are overwritten first, so that they do not linger on the
+        // storage device (or in memory, for unnamed temporary files). The file is removed even if
+        // that fails.
+        let scrubbed = match self.final_mode {
+            Some(_) => scrub_file(&self.file).at_stage(Stage::RemoveTemporaryFile),
+            None => Ok(()),
+        };
         match self.temporary_name {
             None => (),
-            Some(ref temporary_name) => remove_temporary_file(&self.dir, temporary_name)?,
+            Some(ref temporary_name) => remove_temporary_file(self.temporary_dir(), temporary_name)
+                .at_stage(Stage::RemoveTemporaryFile)?,
+        }
+        self.registration = None;
+        scrubbed
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> Option<&OsStr> {
+        if self.name.is_empty() {
+            None
+        } else {
+            Some(&self.name)
         }
-        Ok(())
+    }
+
+    /// Changes the name that the temporary file will be renamed to.
+    pub(crate) fn set_name(&mut self, name: &OsStr) {
+        self.name = name.to_os_string();
+    }
+
+    /// Returns an error if `dir_path` is not the directory that will contain the destination file.
+    pub(crate) fn check_same_dir(&self, dir_path: &Path) -> Result<()> {
+        check_same_dir(&self.dir, dir_path).at_stage(Stage::Rename)
+    }
+
+    /// Returns an error if the destination directory is no longer at `dir_path`.
+    pub(crate) fn check_dir_unchanged(&self, dir_path: &Path) -> Result<()> {
+        check_dir_unchanged(&self.dir, dir_path).at_stage(Stage::CheckDirectory)
+    }
+
+    /// Returns an error if the destination directory is no longer at `dir_path`, relative to
+    /// `base`.
+    #[cfg(feature = "cap-std")]
+    pub(crate) fn check_dir_unchanged_at(
+        &self,
+        base: &cap_std::fs::Dir,
+        dir_path: &Path,
+    ) -> Result<()> {
+        check_dir_unchanged_at(&self.dir, base, dir_path).at_stage(Stage::CheckDirectory)
+    }
+
+    /// Opens the file that the temporary file will be renamed to for reading, if it exists.
+    pub(crate) fn open_destination(&self) -> Result<Option<File>> {
+        open_destination(&self.dir, &self.name).at_stage(Stage::Diff)
+    }
+
+    /// Overwrites the destination file with the contents of the temporary file, without renaming
+    /// it. If `lock` is `true`, the destination file is locked while it's written.
+    pub(crate) fn write_in_place(&self, lock: bool) -> Result<()> {
+        write_in_place(&self.file, &self.dir, &self.name, lock).at_stage(Stage::WriteInPlace)
+    }
+
+    /// Returns a path that can be used to open the temporary file.
+    pub(crate) fn temporary_path(&self) -> Result<PathBuf> {
+        fd_path(&self.file).at_stage(Stage::Validate)
+    }
+
+    #[inline]
+    pub(crate) fn temporary_name(&self) -> Option<&OsStr> {
+        self.temporary_name.as_deref()
+    }
+
+    /// Returns `true` if the temporary file was already renamed to its destination.
+    #[inline]
+    pub(crate) fn is_renamed(&self) -> bool {
+        self.renamed
     }
 
     #[inline]
-    pub(crate) fn directory(&self) -> Option<&Dir> {
+    pub(crate) fn directory(&self) -> Option<&Arc<Dir>> {
         Some(&self.dir)
     }
 }
@@ -1,25 +1,46 @@
+use crate::error;
+use crate::error::ResultExt;
+use crate::error::Stage;
+use crate::imp::NameOptions;
+use crate::imp::Owner;
+use crate::imp::RandomName;
+use crate::imp::copy_contents;
 use nix::errno::Errno;
+use nix::fcntl::AT_FDCWD;
 use nix::fcntl::AtFlags;
+use nix::fcntl::Flock;
+use nix::fcntl::FlockArg;
 use nix::fcntl::OFlag;
 use nix::fcntl::open;
 use nix::fcntl::openat;
 use nix::fcntl::renameat;
 use nix::libc;
+use nix::sys::signal::kill;
+use nix::sys::stat::FileStat;
 use nix::sys::stat::Mode;
 use nix::sys::stat::fchmod;
+use nix::sys::stat::fstat;
 use nix::sys::stat::fstatat;
+use nix::sys::stat::mkdirat;
 use nix::sys::stat::mode_t;
+use nix::sys::stat::stat;
+use nix::time::ClockId;
+use nix::time::clock_gettime;
+use nix::unistd::AccessFlags;
 use nix::unistd::Gid;
+use nix::unistd::Pid;
 use nix::unistd::Uid;
 use nix::unistd::UnlinkatFlags;
+use nix::unistd::faccessat;
 use nix::unistd::fchown;
 use nix::unistd::fsync;
+use nix::unistd::linkat;
 use nix::unistd::unlinkat;
-use rand::Rng;
-use rand::distr::Alphanumeric;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Result;
 use std::os::fd::AsFd;
 use std::os::fd::AsRawFd;
@@ -27,8 +48,12 @@ use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
 use std::os::fd::RawFd;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::ffi::OsStringExt;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 
 #[cfg(all(target_os = "linux", feature = "unnamed-tmpfile"))]
 mod linux;
@@ -42,6 +67,12 @@ mod generic;
 #[cfg(not(all(target_os = "linux", feature = "unnamed-tmpfile")))]
 pub(crate) use self::generic::*;
 
+mod cache;
+pub(crate) mod registry;
+
+#[cfg(feature = "digest")]
+pub(crate) mod xattr;
+
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum Preserve {
     No,
@@ -58,13 +89,46 @@ impl Preserve {
     }
 }
 
+/// An owner to set on temporary files, regardless of the owner of the destination file.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct SetOwner {
+    pub(crate) uid: Option<Uid>,
+    pub(crate) gid: Option<Gid>,
+    /// Whether errors should be reported (`Yes`) or ignored if the process is not privileged
+    /// (`Try`).
+    pub(crate) policy: Preserve,
+}
+
+/// A template file to copy metadata from (see `OpenOptions::metadata_from()`).
+#[derive(Clone, Debug)]
+pub(crate) enum Template {
+    /// A path, resolved like any other ambient path.
+    Path(PathBuf),
+    /// A path, resolved beneath a directory by `cap_std`.
+    #[cfg(feature = "cap-std")]
+    At(Arc<cap_std::fs::Dir>, PathBuf),
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct OpenOptions {
     pub(crate) read: bool,
     pub(crate) mode: mode_t,
     pub(crate) custom_flags: i32,
     pub(crate) preserve_mode: bool,
+    pub(crate) exact_mode: Option<u32>,
     pub(crate) preserve_owner: Preserve,
+    pub(crate) owner: Option<SetOwner>,
+    pub(crate) metadata_from: Option<Template>,
+    pub(crate) secret: bool,
+    pub(crate) pid_in_name: bool,
+    pub(crate) naming: NameOptions,
+    pub(crate) remove_stale: Option<Duration>,
+    pub(crate) cache_dirs: bool,
+    pub(crate) secure_resolution: bool,
+    pub(crate) check_destination: bool,
+    pub(crate) lock_in_place: bool,
+    pub(crate) create_parents: bool,
+    pub(crate) parents_mode: mode_t,
 }
 
 impl OpenOptions {
@@ -74,7 +138,20 @@ impl OpenOptions {
             mode: 0o666,
             custom_flags: 0,
             preserve_mode: true,
+            exact_mode: None,
             preserve_owner: Preserve::Try,
+            owner: None,
+            metadata_from: None,
+            secret: false,
+            pid_in_name: false,
+            naming: NameOptions::new(),
+            remove_stale: None,
+            cache_dirs: false,
+            secure_resolution: false,
+            check_destination: false,
+            lock_in_place: false,
+            create_parents: false,
+            parents_mode: 0o777,
         }
     }
 }
@@ -100,6 +177,57 @@ impl Dir {
         )?;
         Ok(Self { fd })
     }
+
+    /// Opens the directory at `path`, relative to `dir`, refusing to follow symlinks in any of the
+    /// components of `path`.
+    ///
+    /// On Linux, this uses `openat2(2)` with `RESOLVE_NO_SYMLINKS`. On other platforms, or if
+    /// `openat2(2)` is not supported by the kernel, `path` is walked one component at a time,
+    /// opening each component with `O_NOFOLLOW`.
+    pub(crate) fn open_no_symlinks<Fd: AsFd>(dir: Fd, path: &Path) -> nix::Result<Self> {
+        let flags = OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+
+        #[cfg(target_os = "linux")]
+        {
+            use nix::fcntl::OpenHow;
+            use nix::fcntl::ResolveFlag;
+            use nix::fcntl::openat2;
+
+            let how = OpenHow::new()
+                .flags(flags)
+                .resolve(ResolveFlag::RESOLVE_NO_SYMLINKS | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+            match openat2(&dir, path, how) {
+                Err(Errno::ENOSYS) => (),
+                result => return result.map(|fd| Self { fd }),
+            }
+        }
+
+        let flags = flags | OFlag::O_NOFOLLOW;
+        let start = if path.has_root() { "/" } else { "." };
+        let mut fd = openat(&dir, start, flags, Mode::empty())?;
+        for component in path.components() {
+            let name = match component {
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir => OsStr::new(".."),
+                Component::Normal(name) => name,
+            };
+            fd = openat(&fd, name, flags, Mode::empty())?;
+        }
+        Ok(Self { fd })
+    }
+
+    /// Opens the directory referred to by `fd`. This is used to obtain a file descriptor that
+    /// supports all directory operations from one that may not (like one opened with `O_PATH`).
+    #[cfg_attr(not(feature = "cap-std"), allow(dead_code))]
+    pub(crate) fn reopen<Fd: AsFd>(fd: Fd) -> Result<Self> {
+        let fd = openat(
+            fd,
+            ".",
+            OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(Self { fd })
+    }
 }
 
 impl AsFd for Dir {
@@ -116,40 +244,6 @@ impl AsRawFd for Dir {
     }
 }
 
-struct RandomName {
-    buf: Vec<u8>,
-}
-
-impl RandomName {
-    const SUFFIX_SIZE: usize = 6;
-
-    fn new(base_name: &OsStr) -> Self {
-        let buf_len = 1 + base_name.len() + 1 + Self::SUFFIX_SIZE;
-        let mut buf = Vec::with_capacity(buf_len);
-        buf.push(b'.');
-        buf.extend_from_slice(base_name.as_bytes());
-        buf.push(b'.');
-        buf.extend_from_slice(&[0; Self::SUFFIX_SIZE]);
-        debug_assert_eq!(buf_len, buf.len());
-        Self { buf }
-    }
-
-    fn next(&mut self) -> &OsStr {
-        let mut rng = rand::rng();
-        let buf_len = self.buf.len();
-        let suffix = &mut self.buf[buf_len - RandomName::SUFFIX_SIZE..];
-        for c in suffix.iter_mut() {
-            *c = rng.sample(Alphanumeric);
-        }
-        OsStr::from_bytes(&self.buf)
-    }
-
-    #[inline]
-    fn into_os_string(self) -> OsString {
-        OsString::from_vec(self.buf)
-    }
-}
-
 fn create_temporary_file(
     dir: &Dir,
     opts: &OpenOptions,
@@ -165,9 +259,10 @@ fn create_temporary_file(
         | OFlag::O_EXCL
         | OFlag::O_CLOEXEC
         | OFlag::from_bits_truncate(opts.custom_flags & !libc::O_ACCMODE);
-    let create_mode = Mode::from_bits_truncate(opts.mode);
+    let create_mode = create_mode(opts);
 
-    let mut random_name = RandomName::new(name);
+    let owner = temporary_name_owner(opts)?;
+    let mut random_name = RandomName::new(name, owner, &opts.naming);
     let file = loop {
         match openat(dir, random_name.next(), flags, create_mode) {
             Ok(file_fd) => break File::from(file_fd),
@@ -180,9 +275,709 @@ fn create_temporary_file(
     Ok((file, temporary_name))
 }
 
-fn rename_temporary_file(dir: &Dir, temporary_name: &OsStr, name: &OsStr) -> nix::Result<()> {
-    renameat(dir, temporary_name, dir, name)?;
-    fsync(dir)
+/// Opens the directory containing the destination file `path`, or returns a shared handle to it if
+/// directory caching is enabled. See [`open_dir()`] for the meaning of the returned directories.
+fn open_parent_dir<'a>(
+    path: &'a Path,
+    opts: &OpenOptions,
+) -> error::Result<(Arc<Dir>, &'a OsStr, Vec<Dir>)> {
+    let dir_path = path
+        .parent()
+        .ok_or(Errno::EISDIR)
+        .at_stage(Stage::OpenDirectory)?;
+    let name = path
+        .file_name()
+        .ok_or(Errno::EISDIR)
+        .at_stage(Stage::OpenDirectory)?;
+    let (dir, unsynced_parents) = open_dir(dir_path, opts)?;
+    Ok((dir, name, unsynced_parents))
+}
+
+/// Opens the directory at `dir_path`, honoring the options `cache_dirs` and `secure_resolution`.
+///
+/// If `create_parents` is enabled, the directory and its ancestors are created if missing. In that
+/// case, the parents of the directories that were created are also returned: they must be synced
+/// (see [`sync_renamed()`]) for the new directories to survive a crash.
+fn open_dir(dir_path: &Path, opts: &OpenOptions) -> error::Result<(Arc<Dir>, Vec<Dir>)> {
+    let dir_path = if !dir_path.as_os_str().is_empty() {
+        dir_path
+    } else {
+        Path::new(".")
+    };
+    let open = || match (opts.cache_dirs, opts.secure_resolution) {
+        (false, false) => Dir::open(dir_path).map(Arc::new),
+        (true, false) => cache::open_dir(dir_path),
+        (false, true) => Dir::open_no_symlinks(AT_FDCWD, dir_path)
+            .map(Arc::new)
+            .map_err(Error::from),
+        (true, true) => Dir::open_no_symlinks(AT_FDCWD, dir_path)
+            .map_err(Error::from)
+            .and_then(cache::share),
+    };
+    match open() {
+        Err(ref err) if opts.create_parents && err.kind() == ErrorKind::NotFound => {
+            let unsynced_parents =
+                create_dir_all(dir_path, opts).at_stage(Stage::CreateDirectory)?;
+            let dir = open().at_stage(Stage::OpenDirectory)?;
+            Ok((dir, unsynced_parents))
+        }
+        result => result
+            .map(|dir| (dir, Vec::new()))
+            .at_stage(Stage::OpenDirectory),
+    }
+}
+
+/// Creates the directory `dir_path` and its missing ancestors, with mode `opts.parents_mode`
+/// (masked by the umask). Returns the parents of the directories that were created.
+fn create_dir_all(dir_path: &Path, opts: &OpenOptions) -> nix::Result<Vec<Dir>> {
+    let mut flags = OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    if opts.secure_resolution {
+        flags |= OFlag::O_NOFOLLOW;
+    }
+    let open_ancestor = |path: &Path| {
+        let path = if !path.as_os_str().is_empty() {
+            path
+        } else {
+            Path::new(".")
+        };
+        if opts.secure_resolution {
+            Dir::open_no_symlinks(AT_FDCWD, path)
+        } else {
+            open(path, flags, Mode::empty()).map(|fd| Dir { fd })
+        }
+    };
+
+    // Find the closest ancestor that exists
+    let mut missing = Vec::new();
+    let mut ancestor = dir_path;
+    let mut parent = loop {
+        match open_ancestor(ancestor) {
+            Ok(dir) => break dir,
+            Err(Errno::ENOENT) => {
+                missing.push(ancestor.file_name().ok_or(Errno::ENOENT)?);
+                ancestor = ancestor.parent().ok_or(Errno::ENOENT)?;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    let mode = Mode::from_bits_truncate(opts.parents_mode);
+    let mut unsynced_parents = Vec::new();
+    for name in missing.into_iter().rev() {
+        match mkdirat(&parent, name, mode) {
+            // If the directory was created concurrently, it may not have been synced yet: sync its
+            // parent anyway
+            Ok(()) | Err(Errno::EEXIST) => (),
+            Err(err) => return Err(err),
+        }
+        let dir = openat(&parent, name, flags, Mode::empty()).map(|fd| Dir { fd })?;
+        unsynced_parents.push(parent);
+        parent = dir;
+    }
+    Ok(unsynced_parents)
+}
+
+/// Syncs `dir` after the temporary file was renamed into it, so that the rename is durable, and
+/// then the parents of the directories that were created when the file was opened, so that the new
+/// directories survive a crash.
+fn sync_renamed(dir: &Dir, unsynced_parents: &mut Vec<Dir>) -> error::Result<()> {
+    sync_dir(dir)?;
+    for dir in unsynced_parents.iter() {
+        sync_dir(dir)?;
+    }
+    unsynced_parents.clear();
+    Ok(())
+}
+
+fn sync_dir(dir: &Dir) -> error::Result<()> {
+    #[cfg(test)]
+    if fail_sync_dir() {
+        return Err(Errno::EIO).at_stage(Stage::SyncDirectory);
+    }
+    fsync(dir).at_stage(Stage::SyncDirectory)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Used by tests to make the call to [`sync_dir()`] with the given index (counting from 0)
+    /// fail with `EIO`, to simulate failures after the temporary file was renamed.
+    pub(crate) static FAIL_SYNC_DIR: std::cell::Cell<Option<usize>> =
+        const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+fn fail_sync_dir() -> bool {
+    FAIL_SYNC_DIR.with(|fail| match fail.get() {
+        Some(0) => {
+            fail.set(None);
+            true
+        }
+        Some(n) => {
+            fail.set(Some(n - 1));
+            false
+        }
+        None => false,
+    })
+}
+
+/// Opens the file `name` inside `dir` for reading. Returns `None` if the file does not exist.
+///
+/// Returns an error if the file is not a regular file: reading other types of files (like FIFOs or
+/// devices) may block or never end, and symlinks are replaced, not followed, by commits. The file
+/// is checked both before and after it's opened, and it's opened without blocking, in case it's
+/// replaced in the meantime.
+fn open_destination(dir: &Dir, name: &OsStr) -> Result<Option<File>> {
+    #[allow(clippy::unnecessary_cast)]
+    let is_regular = |stat: FileStat| stat.st_mode as mode_t & libc::S_IFMT == libc::S_IFREG;
+    let not_regular = || Error::new(ErrorKind::InvalidInput, "destination is not a regular file");
+
+    match fstatat(dir, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+        Ok(stat) if is_regular(stat) => (),
+        Ok(_) => return Err(not_regular()),
+        Err(Errno::ENOENT) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let flags = OFlag::O_RDONLY
+        | OFlag::O_NONBLOCK
+        | OFlag::O_NOFOLLOW
+        | OFlag::O_NOCTTY
+        | OFlag::O_CLOEXEC;
+    let file = match openat(dir, name, flags, Mode::empty()) {
+        Ok(fd) => File::from(fd),
+        Err(Errno::ENOENT) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if !is_regular(fstat(&file)?) {
+        return Err(not_regular());
+    }
+    Ok(Some(file))
+}
+
+/// Overwrites the contents of the file `name` inside `dir` with the contents of `src`, without
+/// replacing the file. If `lock` is `true`, an exclusive `flock(2)` lock is held on the file while
+/// it's written.
+fn write_in_place(src: &File, dir: &Dir, name: &OsStr, lock: bool) -> Result<()> {
+    let flags = OFlag::O_WRONLY | OFlag::O_NOFOLLOW | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    let dest = File::from(openat(dir, name, flags, Mode::empty())?);
+    if lock {
+        let dest = Flock::lock(dest, FlockArg::LockExclusive).map_err(|(_, err)| err)?;
+        copy_contents(src, &dest)
+    } else {
+        copy_contents(src, &dest)
+    }
+}
+
+/// Returns a path that can be used to open `file`, even if `file` does not have a name.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fd_path(file: &File) -> Result<PathBuf> {
+    Ok(PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())))
+}
+
+/// Returns a path that can be used to open `file`.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+fn fd_path(file: &File) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    nix::fcntl::fcntl(file, nix::fcntl::FcntlArg::F_GETPATH(&mut path))?;
+    Ok(path)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+fn fd_path(_file: &File) -> Result<PathBuf> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "paths of temporary files are not supported on this platform",
+    ))
+}
+
+/// Returns an error if `dir_path` does not refer to the same directory as `dir`.
+fn check_same_dir(dir: &Dir, dir_path: &Path) -> Result<()> {
+    let dir_path = if !dir_path.as_os_str().is_empty() {
+        dir_path
+    } else {
+        Path::new(".")
+    };
+    let expected = fstat(dir)?;
+    let actual = stat(dir_path)?;
+    if actual.st_dev != expected.st_dev {
+        Err(Error::new(
+            ErrorKind::CrossesDevices,
+            "destination is on a different filesystem",
+        ))
+    } else if actual.st_ino != expected.st_ino {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "destination is not in the same directory as the file",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns an error if `dir` was removed, or if `dir_path` no longer refers to `dir` (because the
+/// directory was renamed, or replaced by another one).
+fn check_dir_unchanged(dir: &Dir, dir_path: &Path) -> Result<()> {
+    let dir_path = if !dir_path.as_os_str().is_empty() {
+        dir_path
+    } else {
+        Path::new(".")
+    };
+    // `st_dev` and `st_ino` are not `u64` on all platforms
+    #[allow(clippy::unnecessary_cast)]
+    check_dir_matches(dir, || {
+        stat(dir_path)
+            .map(|stat| (stat.st_dev as u64, stat.st_ino as u64))
+            .map_err(Error::from)
+    })
+}
+
+/// Like [`check_dir_unchanged()`], but with `dir_path` resolved beneath `base` by `cap_std`.
+#[cfg(feature = "cap-std")]
+fn check_dir_unchanged_at(dir: &Dir, base: &cap_std::fs::Dir, dir_path: &Path) -> Result<()> {
+    use cap_std::fs::MetadataExt;
+    let dir_path = if !dir_path.as_os_str().is_empty() {
+        dir_path
+    } else {
+        Path::new(".")
+    };
+    check_dir_matches(dir, || {
+        base.metadata(dir_path)
+            .map(|metadata| (metadata.dev(), metadata.ino()))
+    })
+}
+
+/// Returns an error if `dir` was removed, or if the device and inode numbers returned by `actual`
+/// are not the ones of `dir`.
+fn check_dir_matches<F>(dir: &Dir, actual: F) -> Result<()>
+where
+    F: FnOnce() -> Result<(u64, u64)>,
+{
+    let expected = fstat(dir)?;
+    if expected.st_nlink == 0 {
+        return Err(Error::new(ErrorKind::NotFound, "directory was removed"));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let expected = (expected.st_dev as u64, expected.st_ino as u64);
+    match actual() {
+        Ok(actual) if actual == expected => Ok(()),
+        Ok(_) => Err(Error::new(
+            ErrorKind::NotFound,
+            "directory was moved or replaced",
+        )),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Err(Error::new(
+            ErrorKind::NotFound,
+            "directory was moved or removed",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// Performs the checks requested by `opts` on the directories that will contain the temporary file
+/// and the destination file.
+fn check_dirs(
+    opts: &OpenOptions,
+    dir: &Dir,
+    temporary_dir: Option<&Dir>,
+    name: &OsStr,
+) -> error::Result<()> {
+    if opts.secure_resolution {
+        check_not_foreign_sticky(dir).at_stage(Stage::OpenDirectory)?;
+        if let Some(temporary_dir) = temporary_dir {
+            check_not_foreign_sticky(temporary_dir).at_stage(Stage::OpenTemporaryDirectory)?;
+        }
+    }
+    if opts.check_destination {
+        check_destination(dir, name).at_stage(Stage::CheckDestination)?;
+    }
+    Ok(())
+}
+
+/// Returns an error if the file `name` inside `dir` could not be replaced at commit time: if it's
+/// a directory, a mount point, immutable or append-only, or if new entries cannot be created in
+/// `dir`. If `name` is empty, only `dir` is checked.
+///
+/// These checks are best-effort: they cannot detect all the conditions that make a rename fail,
+/// and the situation may change between the checks and the commit.
+fn check_destination(dir: &Dir, name: &OsStr) -> Result<()> {
+    let dir_stat = fstat(dir)?;
+    check_not_immutable(dir.as_fd(), "directory")?;
+
+    #[cfg(not(target_os = "android"))]
+    let access_flags = AtFlags::AT_EACCESS;
+    #[cfg(target_os = "android")]
+    let access_flags = AtFlags::empty();
+    match faccessat(
+        dir,
+        ".",
+        AccessFlags::W_OK | AccessFlags::X_OK,
+        access_flags,
+    ) {
+        Ok(()) => (),
+        Err(Errno::EACCES) => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "no write and search permission on the directory",
+            ));
+        }
+        Err(Errno::EROFS) => {
+            return Err(Error::new(
+                ErrorKind::ReadOnlyFilesystem,
+                "directory is on a read-only filesystem",
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    if name.is_empty() {
+        return Ok(());
+    }
+    let stat = match fstatat(dir, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+        Ok(stat) => stat,
+        Err(Errno::ENOENT) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    #[allow(clippy::unnecessary_cast)]
+    let file_type = stat.st_mode as mode_t & libc::S_IFMT;
+    if file_type == libc::S_IFDIR {
+        return Err(Error::new(
+            ErrorKind::IsADirectory,
+            "destination is a directory",
+        ));
+    }
+    if is_mount_point(dir, name, &dir_stat, &stat)? {
+        return Err(Error::new(
+            ErrorKind::ResourceBusy,
+            "destination is a mount point",
+        ));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let sticky = dir_stat.st_mode as mode_t & libc::S_ISVTX != 0;
+    let euid = Uid::effective().as_raw();
+    if sticky && stat.st_uid != euid && dir_stat.st_uid != euid && !has_capability(CAP_FOWNER) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "destination is owned by another user in a sticky directory",
+        ));
+    }
+    if file_type == libc::S_IFREG {
+        let flags = OFlag::O_RDONLY
+            | OFlag::O_NONBLOCK
+            | OFlag::O_NOFOLLOW
+            | OFlag::O_NOCTTY
+            | OFlag::O_CLOEXEC;
+        match openat(dir, name, flags, Mode::empty()) {
+            Ok(fd) => check_not_immutable(fd.as_fd(), "destination")?,
+            // Without read access, the flags cannot be inspected: skip the check
+            Err(Errno::EACCES) | Err(Errno::EPERM) => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if the inode `fd` (described as `what` in errors) is immutable or
+/// append-only.
+fn check_not_immutable(fd: BorrowedFd<'_>, what: &str) -> Result<()> {
+    let (immutable, append_only) = inode_flags(fd)?;
+    if immutable {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{what} is immutable"),
+        ))
+    } else if append_only {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{what} is append-only"),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns whether the inode `fd` is immutable and whether it's append-only, according to its
+/// inode flags (`FS_IOC_GETFLAGS`). Filesystems that do not support inode flags have none set.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn inode_flags(fd: BorrowedFd<'_>) -> Result<(bool, bool)> {
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+    const FS_APPEND_FL: libc::c_int = 0x20;
+    // Despite its definition, the kernel reads and writes an `int` for this ioctl
+    let mut flags: libc::c_int = 0;
+    // SAFETY: `FS_IOC_GETFLAGS` writes an `int` to the pointer, which is valid for writes
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    match Errno::result(ret) {
+        Ok(_) => Ok((flags & FS_IMMUTABLE_FL != 0, flags & FS_APPEND_FL != 0)),
+        Err(Errno::ENOTTY) | Err(Errno::EOPNOTSUPP) | Err(Errno::EINVAL) | Err(Errno::ENOSYS) => {
+            Ok((false, false))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns whether the inode `fd` is immutable and whether it's append-only, according to its
+/// file flags (`st_flags`).
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn inode_flags(fd: BorrowedFd<'_>) -> Result<(bool, bool)> {
+    let stat = fstat(fd)?;
+    #[allow(clippy::unnecessary_cast)]
+    let flags = stat.st_flags as u64;
+    let immutable = (libc::UF_IMMUTABLE | libc::SF_IMMUTABLE) as u64;
+    let append_only = (libc::UF_APPEND | libc::SF_APPEND) as u64;
+    Ok((flags & immutable != 0, flags & append_only != 0))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn inode_flags(_fd: BorrowedFd<'_>) -> Result<(bool, bool)> {
+    Ok((false, false))
+}
+
+/// Returns whether `name` inside `dir` is a mount point, given the results of `stat(2)` on `dir`
+/// and on `name`.
+///
+/// Mount points of other filesystems have a different device ID than `dir`, but bind mounts of
+/// files from the same filesystem do not: on Linux, these are detected by comparing mount IDs.
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "android")),
+    allow(unused_variables)
+)]
+fn is_mount_point(dir: &Dir, name: &OsStr, dir_stat: &FileStat, stat: &FileStat) -> Result<bool> {
+    if stat.st_dev != dir_stat.st_dev {
+        return Ok(true);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let (Some(dir_id), Some(id)) = (mount_id(dir, OsStr::new(""))?, mount_id(dir, name)?) {
+        return Ok(id != dir_id);
+    }
+    Ok(false)
+}
+
+/// Returns the ID of the mount that contains `name` inside `dir` (or `dir` itself, if `name` is
+/// empty), without following symlinks, or `None` if the kernel does not report it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mount_id(dir: &Dir, name: &OsStr) -> Result<Option<u64>> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    if let Some(id) = statx_mount_id(dir, name)? {
+        return Ok(Some(id));
+    }
+
+    // Without `STATX_MNT_ID` (before Linux 5.8), the mount ID is reported in `/proc/self/fdinfo`
+    let fd;
+    let fd = if name.is_empty() {
+        dir.as_fd()
+    } else {
+        let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        fd = openat(dir, name, flags, Mode::empty())?;
+        fd.as_fd()
+    };
+    let info = match std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd())) {
+        Ok(info) => info,
+        Err(_) => return Ok(None),
+    };
+    Ok(info
+        .lines()
+        .find_map(|line| line.strip_prefix("mnt_id:"))
+        .and_then(|id| id.trim().parse().ok()))
+}
+
+/// Returns the mount ID of `name` inside `dir` (or of `dir` itself, if `name` is empty) reported
+/// by `statx(2)`, or `None` if not supported by the kernel.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn statx_mount_id(dir: &Dir, name: &OsStr) -> Result<Option<u64>> {
+    use nix::NixPath;
+
+    let mut flags = libc::AT_SYMLINK_NOFOLLOW;
+    if name.is_empty() {
+        flags |= libc::AT_EMPTY_PATH;
+    }
+    // SAFETY: `statx` only contains integers, for which all zeros is a valid value
+    let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+    let result = name.with_nix_path(|name| {
+        // SAFETY: `name` is a valid C string, and `buf` is valid for writes of a `statx`
+        unsafe {
+            libc::statx(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                flags,
+                libc::STATX_MNT_ID,
+                &mut buf,
+            )
+        }
+    })?;
+    match Errno::result(result) {
+        Ok(_) if buf.stx_mask & libc::STATX_MNT_ID != 0 => Ok(Some(buf.stx_mnt_id)),
+        // `STATX_MNT_ID` requires Linux 5.8, and `statx(2)` Linux 4.11
+        Ok(_) | Err(Errno::ENOSYS) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns an error if `dir` has the sticky bit set and is not owned by the effective user.
+///
+/// In such directories (like `/tmp`), other users can create files that we cannot remove or
+/// replace, which may be used to interfere with the creation of temporary files.
+fn check_not_foreign_sticky(dir: &Dir) -> Result<()> {
+    let stat = fstat(dir)?;
+    #[allow(clippy::unnecessary_cast)]
+    let sticky = stat.st_mode as mode_t & libc::S_ISVTX != 0;
+    if sticky && stat.st_uid != Uid::effective().as_raw() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "refusing to operate in a sticky directory not owned by the current user",
+        ));
+    }
+    Ok(())
+}
+
+/// Opens (and creates, if necessary) the directory where temporary files should be placed, if
+/// different from the destination directory.
+fn open_temporary_dir(dir: &Dir, opts: &OpenOptions) -> nix::Result<Option<Dir>> {
+    let path = match opts.naming.dir {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let open = || {
+        if opts.secure_resolution {
+            Dir::open_no_symlinks(dir, path)
+        } else {
+            let flags = OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+            openat(dir, path, flags, Mode::empty()).map(|fd| Dir { fd })
+        }
+    };
+    match open() {
+        Err(Errno::ENOENT) => {
+            match mkdirat(dir, path, Mode::from_bits_truncate(0o777)) {
+                Ok(()) | Err(Errno::EEXIST) => (),
+                Err(err) => return Err(err),
+            }
+            open().map(Some)
+        }
+        result => result.map(Some),
+    }
+}
+
+/// Removes the leftover temporary files for `name`, if requested by `opts`. This is best-effort:
+/// errors are ignored.
+fn remove_stale_temporary_files(
+    dir: &Dir,
+    temporary_dir: Option<&Dir>,
+    name: &OsStr,
+    opts: &OpenOptions,
+) {
+    if let Some(max_age) = opts.remove_stale {
+        let temporary_dir = temporary_dir.unwrap_or(dir);
+        let _ = remove_temporary_files_at(dir, temporary_dir, Some(name), &opts.naming, max_age);
+    }
+}
+
+/// Renames the temporary file to `name`. The caller is responsible for syncing `dir` afterwards
+/// (see [`sync_renamed()`]).
+fn rename_temporary_file(
+    temporary_dir: &Dir,
+    temporary_name: &OsStr,
+    dir: &Dir,
+    name: &OsStr,
+) -> error::Result<()> {
+    renameat(temporary_dir, temporary_name, dir, name).at_stage(Stage::Rename)
+}
+
+/// Opens (and creates, if necessary) the subdirectory `name` of `dir`. If the subdirectory is
+/// created, `dir` is synced, so that the new directory entry is durable.
+#[cfg_attr(not(feature = "store"), allow(dead_code))]
+fn open_or_create_subdir(dir: &Dir, name: &OsStr) -> nix::Result<Dir> {
+    let flags = OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    match openat(dir, name, flags, Mode::empty()) {
+        Err(Errno::ENOENT) => {
+            match mkdirat(dir, name, Mode::from_bits_truncate(0o777)) {
+                Ok(()) => fsync(dir)?,
+                Err(Errno::EEXIST) => (),
+                Err(err) => return Err(err),
+            }
+            openat(dir, name, flags, Mode::empty()).map(|fd| Dir { fd })
+        }
+        result => result.map(|fd| Dir { fd }),
+    }
+}
+
+/// Renames `temporary_name` to `name`, failing with `EEXIST` if `name` already exists.
+#[cfg_attr(not(feature = "store"), allow(dead_code))]
+fn rename_no_replace(
+    temporary_dir: &Dir,
+    temporary_name: &OsStr,
+    dir: &Dir,
+    name: &OsStr,
+) -> nix::Result<()> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        use nix::fcntl::RenameFlags;
+        use nix::fcntl::renameat2;
+
+        match renameat2(
+            temporary_dir,
+            temporary_name,
+            dir,
+            name,
+            RenameFlags::RENAME_NOREPLACE,
+        ) {
+            // `RENAME_NOREPLACE` is not supported by the kernel, or by the filesystem
+            Err(Errno::ENOSYS) | Err(Errno::EINVAL) => (),
+            result => return result,
+        }
+    }
+
+    linkat(temporary_dir, temporary_name, dir, name, AtFlags::empty())?;
+    // The contents are already at their final destination: failing to remove the temporary file
+    // only leaves a leftover behind
+    let _ = unlinkat(temporary_dir, temporary_name, UnlinkatFlags::NoRemoveDir);
+    Ok(())
+}
+
+/// Like [`rename_temporary_file()`], but does not replace `name` if it already exists: in that
+/// case, the temporary file is removed instead. Returns `true` if `name` was created. The caller
+/// should sync `dir` afterwards even if `name` already existed, as it may have been created by
+/// another process that has not synced the directory yet.
+#[cfg_attr(not(feature = "store"), allow(dead_code))]
+fn rename_temporary_file_no_replace(
+    temporary_dir: &Dir,
+    temporary_name: &OsStr,
+    dir: &Dir,
+    name: &OsStr,
+) -> error::Result<bool> {
+    let created = match rename_no_replace(temporary_dir, temporary_name, dir, name) {
+        Ok(()) => true,
+        Err(Errno::EEXIST) => {
+            unlinkat(temporary_dir, temporary_name, UnlinkatFlags::NoRemoveDir)
+                .at_stage(Stage::RemoveTemporaryFile)?;
+            false
+        }
+        Err(err) => return Err(err).at_stage(Stage::Rename),
+    };
+    Ok(created)
 }
 
 fn remove_temporary_file(dir: &Dir, temporary_name: &OsStr) -> nix::Result<()> {
@@ -190,11 +985,199 @@ fn remove_temporary_file(dir: &Dir, temporary_name: &OsStr) -> nix::Result<()> {
     fsync(dir)
 }
 
+/// Returns the time elapsed since boot, in seconds.
+fn uptime() -> nix::Result<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let clock = ClockId::CLOCK_BOOTTIME;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let clock = ClockId::CLOCK_MONOTONIC;
+    let now = clock_gettime(clock)?;
+    Ok(u64::try_from(now.tv_sec()).unwrap_or(0))
+}
+
+fn current_owner() -> nix::Result<Owner> {
+    Ok(Owner {
+        pid: std::process::id(),
+        timestamp: uptime()?,
+    })
+}
+
+/// Returns the owner to include in the names of temporary files, if requested by `opts`.
+pub(crate) fn temporary_name_owner(opts: &OpenOptions) -> nix::Result<Option<Owner>> {
+    if opts.pid_in_name {
+        current_owner().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the start time of the process `pid`, in seconds since boot.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn process_start_time(pid: u32) -> Option<u64> {
+    use nix::unistd::SysconfVar;
+    use nix::unistd::sysconf;
+
+    let stat = std::fs::read(format!("/proc/{pid}/stat")).ok()?;
+    // The second field (the command name) may contain spaces and parentheses, so skip past the
+    // last closing parenthesis. The start time is the 22nd field, or the 20th after the command
+    // name.
+    let comm_end = stat.iter().rposition(|&c| c == b')')?;
+    let start_time = stat[comm_end + 1..]
+        .split(u8::is_ascii_whitespace)
+        .filter(|field| !field.is_empty())
+        .nth(19)?;
+    let ticks: u64 = std::str::from_utf8(start_time).ok()?.parse().ok()?;
+    let ticks_per_second = u64::try_from(sysconf(SysconfVar::CLK_TCK).ok()??).ok()?;
+    ticks.checked_div(ticks_per_second)
+}
+
+/// Returns `true` if the process that created a temporary file may still be running.
+///
+/// When in doubt, this returns `true`, so that temporary files that may still be in use are not
+/// removed. A return value of `false` does not mean that the process has exited: it may be running
+/// on another machine, or in another PID namespace, where its ID refers to a different process
+/// (or to no process at all). For this reason, the caller must still check the age of the
+/// temporary file before removing it.
+fn is_owner_alive(owner: Owner) -> bool {
+    // A temporary file that was created after the current uptime must have been created before
+    // the last boot
+    match uptime() {
+        Ok(now) if owner.timestamp > now => return false,
+        _ => (),
+    }
+    let pid = match i32::try_from(owner.pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    match kill(Pid::from_raw(pid), None) {
+        Ok(()) | Err(Errno::EPERM) => (),
+        // Not visible from this PID namespace: fall back to the age check
+        Err(Errno::ESRCH) => return false,
+        Err(_) => return true,
+    }
+    // The process ID may have been reused by a process that was started after the temporary file
+    // was created
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(start_time) = process_start_time(owner.pid) {
+        if start_time > owner.timestamp {
+            return false;
+        }
+    }
+    true
+}
+
+fn is_older_than(modified: SystemTime, max_age: Duration) -> bool {
+    match SystemTime::now().duration_since(modified) {
+        Ok(age) => age >= max_age,
+        // The modification time is in the future
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn remove_temporary_files(
+    dir_path: &Path,
+    base_name: Option<&OsStr>,
+    naming: &NameOptions,
+    max_age: Duration,
+) -> Result<Vec<OsString>> {
+    let dir = if !dir_path.as_os_str().is_empty() {
+        Dir::open(dir_path)?
+    } else {
+        Dir::open(".")?
+    };
+    let temporary_dir = match naming.dir {
+        Some(ref path) => Some(Dir {
+            fd: openat(
+                &dir,
+                path,
+                OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?,
+        }),
+        None => None,
+    };
+    let temporary_dir = temporary_dir.as_ref().unwrap_or(&dir);
+    remove_temporary_files_at(&dir, temporary_dir, base_name, naming, max_age)
+}
+
+/// Removes the leftover temporary files in `temporary_dir` for the file `base_name` in `dir`.
+///
+/// If `base_name` is `None`, temporary files for any file are removed, as long as they can be
+/// positively identified: either their name embeds the ID of the process that created them, or
+/// their destination file exists in `dir`. This avoids removing unrelated files that just happen
+/// to follow the same naming scheme.
+pub(crate) fn remove_temporary_files_at(
+    dir: &Dir,
+    temporary_dir: &Dir,
+    base_name: Option<&OsStr>,
+    naming: &NameOptions,
+    max_age: Duration,
+) -> Result<Vec<OsString>> {
+    let mut entries = nix::dir::Dir::openat(
+        temporary_dir,
+        ".",
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let mut removed = Vec::new();
+
+    for entry in entries.iter() {
+        let entry = entry?;
+        let name = OsStr::from_bytes(entry.file_name().to_bytes());
+        let parsed = match RandomName::parse(name, base_name, naming) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        // Temporary files of running processes are always kept. All other temporary files are
+        // subject to the age check, because their process may just not be visible
+        match parsed.owner {
+            Some(owner) if is_owner_alive(owner) => continue,
+            Some(_) => (),
+            None if base_name.is_some() => (),
+            None => match fstatat(dir, parsed.base_name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+                Ok(_) => (),
+                Err(Errno::ENOENT) => continue,
+                Err(err) => return Err(err.into()),
+            },
+        }
+
+        // The entry may have been committed or removed by its owner since it was listed
+        let stat = match fstatat(temporary_dir, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) => stat,
+            Err(Errno::ENOENT) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        #[allow(clippy::unnecessary_cast)]
+        if stat.st_mode as mode_t & libc::S_IFMT != libc::S_IFREG {
+            continue;
+        }
+        let modified = SystemTime::UNIX_EPOCH
+            + Duration::new(
+                u64::try_from(stat.st_mtime).unwrap_or(0),
+                stat.st_mtime_nsec as u32,
+            );
+        if !is_older_than(modified, max_age) {
+            continue;
+        }
+
+        match unlinkat(temporary_dir, name, UnlinkatFlags::NoRemoveDir) {
+            Ok(()) => removed.push(name.to_os_string()),
+            Err(Errno::ENOENT) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    if !removed.is_empty() {
+        fsync(temporary_dir)?;
+    }
+    Ok(removed)
+}
+
 fn maybe_ignore_eperm(result: nix::Result<()>, preserve: Preserve) -> nix::Result<()> {
     match result {
         Err(Errno::EPERM) => match preserve {
             Preserve::Try => {
-                if Uid::effective().is_root() {
+                if has_capability(CAP_CHOWN) {
                     result
                 } else {
                     Ok(())
@@ -206,28 +1189,201 @@ fn maybe_ignore_eperm(result: nix::Result<()>, preserve: Preserve) -> nix::Resul
     }
 }
 
-fn copy_file_perms<P: AsRef<Path>>(
+/// Capability to change the owner of any file.
+const CAP_CHOWN: u32 = 0;
+
+/// Capability to bypass the checks that require the process to own a file (including the
+/// restrictions of sticky directories).
+const CAP_FOWNER: u32 = 3;
+
+/// Returns `true` if the process has the capability `cap`.
+///
+/// On Linux, this checks whether `cap` is in the effective capability set of the process, so that
+/// root users without the capability (for example, in containers) and non-root users with the
+/// capability are handled correctly. If the capabilities cannot be determined, or on other
+/// platforms, this checks whether the process is running as root.
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "android")),
+    allow(unused_variables)
+)]
+fn has_capability(cap: u32) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let effective =
+            proc_self_status("CapEff").and_then(|caps| u64::from_str_radix(&caps, 16).ok());
+        if let Some(effective) = effective {
+            return effective & (1 << cap) != 0;
+        }
+    }
+    Uid::effective().is_root()
+}
+
+/// Returns the value of `field` in `/proc/self/status`, if present.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn proc_self_status(field: &str) -> Option<String> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status.lines().find_map(|line| {
+        let value = line.strip_prefix(field)?.strip_prefix(':')?;
+        Some(value.trim().to_owned())
+    })
+}
+
+/// Returns the file mode creation mask of the process, without changing it.
+///
+/// The umask can only be read atomically from `/proc/self/status` (on Linux 4.7 and later):
+/// `umask(2)` changes it, which is not safe in multi-threaded processes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn current_umask() -> Option<mode_t> {
+    proc_self_status("Umask").and_then(|umask| mode_t::from_str_radix(&umask, 8).ok())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn current_umask() -> Option<mode_t> {
+    None
+}
+
+/// Returns the mode to create temporary files with.
+fn create_mode(opts: &OpenOptions) -> Mode {
+    if opts.secret {
+        Mode::from_bits_truncate(0o600)
+    } else {
+        Mode::from_bits_truncate(opts.mode)
+    }
+}
+
+/// Overwrites the contents of `file` with zeros, and flushes them to the storage device.
+///
+/// This is best-effort: on copy-on-write filesystems, and on storage devices that remap blocks,
+/// the original contents may survive.
+fn scrub_file(file: &File) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    let len = file.metadata()?.len();
+    let zeros = [0u8; 8192];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(zeros.len() as u64) as usize;
+        file.write_all_at(&zeros[..n], offset)?;
+        offset += n as u64;
+    }
+    file.sync_data()
+}
+
+/// The metadata that [`set_file_perms()`] can copy from another file.
+#[derive(Copy, Clone, Debug)]
+struct SourcePerms {
+    mode: mode_t,
+    uid: Uid,
+    gid: Gid,
+}
+
+impl From<FileStat> for SourcePerms {
+    fn from(stat: FileStat) -> Self {
+        Self {
+            // On `androideabi`, `mode_t` is not `u32` but `u16`
+            #[allow(clippy::unnecessary_cast)]
+            mode: stat.st_mode as mode_t,
+            uid: Uid::from_raw(stat.st_uid),
+            gid: Gid::from_raw(stat.st_gid),
+        }
+    }
+}
+
+#[cfg(feature = "cap-std")]
+impl From<cap_std::fs::Metadata> for SourcePerms {
+    fn from(metadata: cap_std::fs::Metadata) -> Self {
+        use cap_std::fs::MetadataExt;
+        Self {
+            mode: metadata.mode() as mode_t,
+            uid: Uid::from_raw(metadata.uid()),
+            gid: Gid::from_raw(metadata.gid()),
+        }
+    }
+}
+
+/// Sets the owner and the mode of `file`, as requested by `opts`. If requested, the owner and the
+/// mode are copied from the template file in `opts.metadata_from` (which must exist), or else from
+/// the file `copy_from` inside `dir` (if it exists).
+///
+/// The owner is set before the mode, because changing the owner may clear the setuid and setgid
+/// bits. In secret mode, the mode is not set: it's returned, so that it can be set at commit time.
+fn set_file_perms<P: AsRef<Path>>(
     dir: &Dir,
     copy_from: P,
-    copy_to: &File,
+    file: &File,
     opts: &OpenOptions,
-) -> Result<()> {
-    let stat = match fstatat(dir, copy_from.as_ref(), AtFlags::AT_SYMLINK_NOFOLLOW) {
-        Ok(stat) => stat,
-        Err(Errno::ENOENT) => return Ok(()),
-        Err(err) => return Err(err.into()),
+) -> error::Result<Option<Mode>> {
+    let copy_mode = opts.preserve_mode && opts.exact_mode.is_none();
+    let copy_owner = opts.preserve_owner.is_yes() && opts.owner.is_none();
+    let source = if copy_mode || copy_owner {
+        let stage = if copy_mode {
+            Stage::CopyPermissions
+        } else {
+            Stage::CopyOwner
+        };
+        match opts.metadata_from {
+            Some(Template::Path(ref template)) => Some(
+                stat(template.as_path())
+                    .map(SourcePerms::from)
+                    .map_err(|err| error::Error::new(stage, err))?,
+            ),
+            #[cfg(feature = "cap-std")]
+            Some(Template::At(ref template_dir, ref template)) => Some(
+                template_dir
+                    .metadata(template)
+                    .map(SourcePerms::from)
+                    .map_err(|err| error::Error::new(stage, err))?,
+            ),
+            None => match fstatat(dir, copy_from.as_ref(), AtFlags::AT_SYMLINK_NOFOLLOW) {
+                Ok(stat) => Some(SourcePerms::from(stat)),
+                Err(Errno::ENOENT) => None,
+                Err(err) => return Err(error::Error::new(stage, err)),
+            },
+        }
+    } else {
+        None
     };
-    if opts.preserve_mode {
-        // On `androideabi`, `mode_t` is not `u32` but `u16`, hence the cast. This will truncate
-        // the higher bits, but that is fine as those bits can't have any effect.
-        #[allow(clippy::unnecessary_cast)]
-        let mode = Mode::from_bits_retain(stat.st_mode as mode_t);
-        fchmod(copy_to, mode)?;
+
+    if let Some(owner) = opts.owner {
+        maybe_ignore_eperm(fchown(file, owner.uid, owner.gid), owner.policy)
+            .at_stage(Stage::SetOwner)?;
+    } else if let (true, Some(source)) = (copy_owner, source) {
+        maybe_ignore_eperm(
+            fchown(file, Some(source.uid), Some(source.gid)),
+            opts.preserve_owner,
+        )
+        .at_stage(Stage::CopyOwner)?;
     }
-    if opts.preserve_owner.is_yes() {
-        let uid = Uid::from_raw(stat.st_uid);
-        let gid = Gid::from_raw(stat.st_gid);
-        maybe_ignore_eperm(fchown(copy_to, Some(uid), Some(gid)), opts.preserve_owner)?;
+
+    // On `androideabi`, `mode_t` is not `u32` but `u16`, hence the casts. This will truncate the
+    // higher bits, but that is fine as those bits can't have any effect.
+    #[allow(clippy::unnecessary_cast)]
+    let mode = if let Some(mode) = opts.exact_mode {
+        // Bits other than the permission bits (like the file type bits, which are present in
+        // modes obtained from metadata) are ignored
+        Some((
+            Mode::from_bits_truncate((mode & 0o7777) as mode_t),
+            Stage::SetPermissions,
+        ))
+    } else if let (true, Some(source)) = (copy_mode, source) {
+        Some((Mode::from_bits_retain(source.mode), Stage::CopyPermissions))
+    } else {
+        None
+    };
+
+    if opts.secret {
+        let mode = match mode {
+            Some((mode, _)) => mode,
+            // The mode that the file would have been created with, or the most restrictive one if
+            // the umask is not known
+            None => match current_umask() {
+                Some(umask) => Mode::from_bits_truncate(opts.mode & !umask),
+                None => Mode::from_bits_truncate(0o600),
+            },
+        };
+        return Ok(Some(mode));
     }
-    Ok(())
+    if let Some((mode, stage)) = mode {
+        fchmod(file, mode).at_stage(stage)?;
+    }
+    Ok(None)
 }
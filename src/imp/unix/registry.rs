@@ -0,0 +1,207 @@
+//! Global registry of named temporary files, used to remove them when the process exits or is
+//! terminated by a signal.
+//!
+//! The registry is a lock-free linked list of nodes. Nodes are never deallocated: when a temporary
+//! file is unregistered, its node is marked as free and can be reused by another temporary file.
+//! This allows [`remove_all()`] to walk the list from a signal handler without taking locks or
+//! allocating memory.
+
+use crate::imp::unix::Dir;
+use nix::libc;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+/// The node is not in use.
+const FREE: u8 = 0;
+/// The node is being filled or emptied by its owner.
+const CLAIMED: u8 = 1;
+/// The node refers to a temporary file that should be removed.
+const ACTIVE: u8 = 2;
+/// The node is being used by [`remove_all()`].
+const BUSY: u8 = 3;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HEAD: AtomicPtr<Node> = AtomicPtr::new(ptr::null_mut());
+static AT_EXIT: Once = Once::new();
+
+#[derive(Debug)]
+struct Node {
+    state: AtomicU8,
+    dir_fd: AtomicI32,
+    name: AtomicPtr<libc::c_char>,
+    next: AtomicPtr<Node>,
+}
+
+impl Node {
+    fn iter() -> impl Iterator<Item = &'static Node> {
+        let mut ptr = HEAD.load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            // SAFETY: nodes are never deallocated, so any pointer found in the list is either null
+            // or valid for the entire lifetime of the process
+            let node = unsafe { ptr.as_ref() }?;
+            ptr = node.next.load(Ordering::Acquire);
+            Some(node)
+        })
+    }
+
+    fn claim() -> &'static Node {
+        for node in Node::iter() {
+            if node
+                .state
+                .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return node;
+            }
+        }
+
+        let node: &'static Node = Box::leak(Box::new(Node {
+            state: AtomicU8::new(CLAIMED),
+            dir_fd: AtomicI32::new(-1),
+            name: AtomicPtr::new(ptr::null_mut()),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let node_ptr = node as *const Node as *mut Node;
+        let mut head = HEAD.load(Ordering::Relaxed);
+        loop {
+            node.next.store(head, Ordering::Relaxed);
+            match HEAD.compare_exchange_weak(head, node_ptr, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return node,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// A temporary file in the registry. The temporary file is unregistered when this is dropped.
+///
+/// This must be dropped before the directory file descriptor is closed.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    node: &'static Node,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // If `remove_all()` is using the node from another thread, wait for it to finish
+        while self
+            .node
+            .state
+            .compare_exchange_weak(ACTIVE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        let name = self.node.name.swap(ptr::null_mut(), Ordering::Relaxed);
+        // SAFETY: `name` was obtained from `CString::into_raw()` in `register()`, and the node is
+        // now claimed, so nobody else can access it
+        drop(unsafe { CString::from_raw(name) });
+        self.node.state.store(FREE, Ordering::Release);
+    }
+}
+
+/// Enables the registry. Only temporary files created after the registry is enabled are
+/// registered.
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Adds the temporary file `name` inside `dir` to the registry, if the registry is enabled.
+pub(crate) fn register(dir: &Dir, name: &OsStr) -> Option<Registration> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    // Temporary file names never contain NUL bytes
+    let name = CString::new(name.as_bytes()).ok()?;
+    let node = Node::claim();
+    node.dir_fd.store(dir.as_raw_fd(), Ordering::Relaxed);
+    node.name.store(name.into_raw(), Ordering::Relaxed);
+    node.state.store(ACTIVE, Ordering::Release);
+    Some(Registration { node })
+}
+
+/// Removes all the registered temporary files.
+///
+/// This function is async-signal-safe.
+pub(crate) fn remove_all() {
+    for node in Node::iter() {
+        if node
+            .state
+            .compare_exchange(ACTIVE, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            let dir_fd = node.dir_fd.load(Ordering::Relaxed);
+            let name = node.name.load(Ordering::Relaxed);
+            // SAFETY: `name` is a valid NUL-terminated string, which cannot be deallocated while
+            // the node is busy. Errors are ignored: the temporary file may have been already
+            // removed.
+            unsafe { libc::unlinkat(dir_fd, name, 0) };
+            node.state.store(ACTIVE, Ordering::Release);
+        }
+    }
+}
+
+extern "C" fn remove_all_at_exit() {
+    remove_all();
+}
+
+extern "C" fn remove_all_on_signal(signal: libc::c_int) {
+    remove_all();
+    // The handler was installed with `SA_RESETHAND`, so now the default action is restored.
+    // Raising the signal again ensures that the process terminates (or does whatever the default
+    // action is) as if the handler was never installed.
+    //
+    // SAFETY: `raise` is async-signal-safe
+    unsafe { libc::raise(signal) };
+}
+
+/// Enables the registry and arranges for registered temporary files to be removed when the process
+/// exits via `exit(3)`.
+pub(crate) fn remove_all_at_exit_hook() -> nix::Result<()> {
+    enable();
+    let mut result = Ok(());
+    AT_EXIT.call_once(|| {
+        // SAFETY: `remove_all_at_exit` is a valid function that does not unwind
+        if unsafe { libc::atexit(remove_all_at_exit) } != 0 {
+            result = Err(nix::errno::Errno::ENOMEM);
+        }
+    });
+    result
+}
+
+/// Enables the registry and installs handlers for `signals` that remove registered temporary
+/// files.
+pub(crate) fn remove_all_on_signals(signals: &[libc::c_int]) -> nix::Result<()> {
+    use nix::sys::signal::SaFlags;
+    use nix::sys::signal::SigAction;
+    use nix::sys::signal::SigHandler;
+    use nix::sys::signal::SigSet;
+    use nix::sys::signal::Signal;
+    use nix::sys::signal::sigaction;
+
+    let signals = signals
+        .iter()
+        .map(|&signal| Signal::try_from(signal))
+        .collect::<nix::Result<Vec<Signal>>>()?;
+
+    enable();
+    let action = SigAction::new(
+        SigHandler::Handler(remove_all_on_signal),
+        SaFlags::SA_RESETHAND,
+        SigSet::empty(),
+    );
+    for signal in signals {
+        // SAFETY: the handler is async-signal-safe
+        unsafe { sigaction(signal, &action) }?;
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Minimal wrappers around the extended attributes system calls, which are not provided by `nix`.
+
+use nix::NixPath;
+use nix::errno::Errno;
+use nix::libc;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Sets the extended attribute `name` of `file` to `value`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+pub(crate) fn set(file: &File, name: &CStr, value: &[u8]) -> Result<()> {
+    let fd = file.as_raw_fd();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    // SAFETY: `name` is a valid C string, and `value` is valid for reads of `value.len()` bytes
+    let result =
+        unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    // SAFETY: `name` is a valid C string, and `value` is valid for reads of `value.len()` bytes
+    let result =
+        unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0, 0) };
+
+    Errno::result(result)?;
+    Ok(())
+}
+
+/// Returns the value of the extended attribute `name` of the file at `path`, following symlinks.
+///
+/// Values longer than `max_len` are rejected with an error of kind
+/// [`InvalidData`](ErrorKind::InvalidData). A missing attribute is reported with an error of kind
+/// [`NotFound`](ErrorKind::NotFound), like a missing file.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+pub(crate) fn get(path: &Path, name: &CStr, max_len: usize) -> Result<Vec<u8>> {
+    let mut value = vec![0u8; max_len];
+
+    let result = path.with_nix_path(|path| {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        // SAFETY: `path` and `name` are valid C strings, and `value` is valid for writes of
+        // `value.len()` bytes
+        let result = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        // SAFETY: `path` and `name` are valid C strings, and `value` is valid for writes of
+        // `value.len()` bytes
+        let result = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+                0,
+                0,
+            )
+        };
+
+        result
+    })?;
+
+    let len = match Errno::result(result) {
+        Ok(len) => len as usize,
+        Err(Errno::ERANGE) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "extended attribute value is too long",
+            ));
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Err(Errno::ENODATA) => return Err(not_found()),
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        Err(Errno::ENOATTR) => return Err(not_found()),
+        Err(err) => return Err(err.into()),
+    };
+    value.truncate(len);
+    Ok(value)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "extended attribute not found")
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub(crate) fn set(_file: &File, _name: &CStr, _value: &[u8]) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub(crate) fn get(_path: &Path, _name: &CStr, _max_len: usize) -> Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "extended attributes are not supported on this platform",
+    )
+}
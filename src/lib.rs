@@ -100,11 +100,17 @@
 //! * If the path of an [`AtomicWriteFile`] is a directory or a file that cannot be removed (due to
 //!   permissions or special attributes), an error will be produced when the [`AtomicWriteFile`] is
 //!   committed. This is in contrast with the standard `File`, which would instead produce an error
-//!   at `open()` time.
+//!   at `open()` time. [`OpenOptions::check_destination()`] can be used to detect the most common
+//!   of these conditions when the file is opened, and [`OpenOptions::in_place_fallback()`] can be
+//!   used to write to files that can be modified but not replaced (non-atomically).
 //!
 //! * [`AtomicWriteFile`] is designed so that the temporary files it creates are automatically
 //!   removed if an error (such as a panic) occurs. However, if the process is interrupted abruptly
 //!   (without unwinding or running destructors), temporary files may be left on the filesystem.
+//!   [`CleanupOptions`] can be used to find and remove these leftover temporary files. On Unix,
+//!   temporary files can also be removed when the process exits or is terminated by a signal: see
+//!   [`unix::remove_tracked_temporary_files_at_exit()`] and
+//!   [`unix::remove_tracked_temporary_files_on_signals()`].
 //!
 //! * If the path of an [`AtomicWriteFile`] is a symlink to another file, the symlink is replaced,
 //!   and the target of the original symlink is left untouched. If you intend to modify the file
@@ -162,7 +168,59 @@
 //!   with `unnamed-tmpfile` unsuitable for use in processes that run early at boot.
 //!
 //! This feature has no effect on platforms other than Linux.
-
+//!
+//! ## `cap-std` (Unix only)
+//!
+#![cfg_attr(
+    feature = "cap-std",
+    doc = "Adds [`OpenOptions::open_at()`], which opens an [`AtomicWriteFile`] relative to a"
+)]
+#![cfg_attr(
+    not(feature = "cap-std"),
+    doc = "Adds `OpenOptions::open_at()`, which opens an [`AtomicWriteFile`] relative to a"
+)]
+#![cfg_attr(feature = "cap-std", doc = "[`cap_std::fs::Dir`],")]
+#![cfg_attr(not(feature = "cap-std"), doc = "`cap_std::fs::Dir`,")]
+//! with all path resolution confined beneath that directory. This is useful for sandboxed
+//! programs that follow a capability-based security model, and that are not allowed to resolve
+//! ambient paths.
+//!
+//! This feature has no effect on platforms other than Unix.
+//!
+//! ## `store`
+//!
+#![cfg_attr(feature = "store", doc = "Adds the [`store`] module,")]
+#![cfg_attr(not(feature = "store"), doc = "Adds the `store` module,")]
+//! which implements a content-addressed store of files (blobs) on top of [`AtomicWriteFile`].
+//! Blobs are named after the SHA-256 hash of their contents, which is computed while they're
+//! written.
+//!
+//! ## `digest`
+//!
+#![cfg_attr(
+    feature = "digest",
+    doc = "Adds [`OpenOptions::compute_digest()`], [`AtomicWriteFile::digest()`], and",
+    doc = "[`AtomicWriteFile::commit_if_digest()`],"
+)]
+#![cfg_attr(
+    not(feature = "digest"),
+    doc = "Adds `OpenOptions::compute_digest()`, `AtomicWriteFile::digest()`, and",
+    doc = "`AtomicWriteFile::commit_if_digest()`,"
+)]
+//! which compute the SHA-256 digest of the contents of an [`AtomicWriteFile`], and can be used to
+//! verify the contents before they're committed. It also adds
+#![cfg_attr(
+    feature = "digest",
+    doc = "[`OpenOptions::record_digest()`](crate::OpenOptions::record_digest),"
+)]
+#![cfg_attr(not(feature = "digest"), doc = "`OpenOptions::record_digest()`,")]
+//! which records the digest of the contents when the file is committed, so that it can be checked
+//! later with
+#![cfg_attr(
+    feature = "digest",
+    doc = "[`DigestRecord::verify()`](crate::DigestRecord::verify)."
+)]
+#![cfg_attr(not(feature = "digest"), doc = "`DigestRecord::verify()`.")]
 #![warn(clippy::dbg_macro)]
 #![warn(clippy::print_stderr)]
 #![warn(clippy::print_stdout)]
@@ -188,8 +246,12 @@
 )]
 #![cfg_attr(feature = "unstable-write_all_vectored", feature(write_all_vectored))]
 
+use std::ffi::OsStr;
 use std::fmt::Arguments;
 use std::fs::File;
+use std::fs::Permissions;
+use std::io;
+use std::io::ErrorKind;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
 use std::io::Read;
@@ -199,22 +261,50 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(feature = "unstable-read_buf")]
 use std::io::BorrowedCursor;
 
 mod imp;
 
+#[cfg(all(unix, feature = "cap-std"))]
+mod cap;
+
+mod cleanup;
+pub use cleanup::CleanupOptions;
+
+mod error;
+pub use error::CommitError;
+pub use error::Error;
+use error::ResultExt;
+pub use error::Stage;
+
 mod dir;
 pub use dir::Directory;
 
+mod digest;
+#[cfg(feature = "digest")]
+pub use digest::DigestRecord;
+
+#[cfg(any(feature = "digest", feature = "store"))]
+mod sha256;
+
+pub mod diff;
+
 #[cfg(any(unix, target_os = "wasi"))]
 mod fd;
 
 #[cfg(unix)]
 pub mod unix;
 
+#[cfg(feature = "store")]
+pub mod store;
+
 #[cfg(test)]
 mod tests;
 
@@ -278,6 +368,12 @@ mod tests;
 #[derive(Clone, Debug)]
 pub struct OpenOptions {
     inner: imp::OpenOptions,
+    #[cfg(feature = "digest")]
+    compute_digest: bool,
+    #[cfg(feature = "digest")]
+    record_digest: Option<DigestRecord>,
+    in_place_fallback: bool,
+    check_directory: bool,
 }
 
 impl OpenOptions {
@@ -285,6 +381,12 @@ impl OpenOptions {
     pub fn new() -> Self {
         Self {
             inner: imp::OpenOptions::new(),
+            #[cfg(feature = "digest")]
+            compute_digest: false,
+            #[cfg(feature = "digest")]
+            record_digest: None,
+            in_place_fallback: false,
+            check_directory: false,
         }
     }
 
@@ -321,6 +423,457 @@ impl OpenOptions {
         self
     }
 
+    /// Sets the permissions of the file.
+    ///
+    /// The permissions are set on the temporary file when it's opened, and take precedence over
+    /// the permissions of the original file (if any). On Unix, this is equivalent to
+    /// [`unix::OpenOptionsExt::exact_mode()`]: the mode is set with `fchmod(2)`, and it's not
+    /// affected by the process umask.
+    ///
+    /// By default, the permissions are chosen by the platform (see
+    /// [`unix::OpenOptionsExt::preserve_mode()`] for Unix).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// # std::fs::write("template.txt", "")?;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let mut permissions = std::fs::metadata("template.txt")?.permissions();
+    /// permissions.set_readonly(true);
+    ///
+    /// let file = OpenOptions::new()
+    ///     .permissions(permissions)
+    ///     .open("read-only.txt")?;
+    /// file.commit()?; // "read-only.txt" is saved as read-only
+    /// # std::fs::remove_file("read-only.txt")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn permissions(&mut self, permissions: Permissions) -> &mut Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.inner.exact_mode = Some(permissions.mode());
+        }
+        #[cfg(not(unix))]
+        {
+            self.inner.permissions = Some(permissions);
+        }
+        self
+    }
+
+    /// Sets a template file to copy the metadata of the file from, instead of the destination
+    /// file.
+    ///
+    /// By default, the metadata of the destination file (if it exists) is copied to the file when
+    /// it's opened (see [`unix::OpenOptionsExt`]). This option makes the file copy the metadata of
+    /// the template at `path` instead, which is useful to give new files the same permissions and
+    /// owner of a similar file. The options that control which metadata is preserved (like
+    /// [`unix::OpenOptionsExt::preserve_mode()`]) apply to the template in the same way, and
+    /// explicit permissions or owners (like [`OpenOptions::permissions()`]) still take precedence.
+    /// On platforms other than Unix, only the permissions are copied.
+    ///
+    /// If `path` is a symlink, the metadata of the file it points to is copied. If `path` does
+    /// not exist, [`OpenOptions::open()`] fails with an error of kind
+    /// [`NotFound`](std::io::ErrorKind::NotFound). Files opened with `open_at()` (available with
+    /// the `cap-std` feature) resolve `path` beneath their capability directory instead, and fail
+    /// if it refers to a location outside of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// # std::fs::write("config.default", "")?;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let file = OpenOptions::new()
+    ///     .metadata_from("config.default")
+    ///     .open("config.local")?;
+    /// file.commit()?; // "config.local" has the same permissions as "config.default"
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn metadata_from<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        #[cfg(unix)]
+        {
+            self.inner.metadata_from = Some(imp::Template::Path(path.as_ref().to_path_buf()));
+        }
+        #[cfg(not(unix))]
+        {
+            self.inner.metadata_from = Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    /// Sets the option to check, when the file is opened, that the destination file can be
+    /// replaced.
+    ///
+    /// Normally, if the destination cannot be replaced (for example, because it's a directory),
+    /// the error is only detected when the file is committed, after all the contents have been
+    /// written. If this option is `true`, [`OpenOptions::open()`] fails early, with an error of
+    /// stage [`Stage::CheckDestination`] that describes the problem, if:
+    ///
+    /// * the destination is a directory;
+    /// * the destination is a mount point (on Unix, if it's on a different filesystem than its
+    ///   directory; on Linux, bind mounts from the same filesystem are detected too);
+    /// * the destination, or its directory, is immutable or append-only (on Unix, on platforms
+    ///   and filesystems that support these flags);
+    /// * the directory cannot be written to or searched by the current user (on Unix);
+    /// * the directory has the sticky bit set, and the destination is owned by another user (on
+    ///   Unix);
+    /// * the destination is read-only (on platforms other than Unix).
+    ///
+    /// If the destination does not exist, only its directory is checked. These checks are
+    /// best-effort: the conditions may change before the file is committed, and other errors may
+    /// still occur at commit time. The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// # std::fs::create_dir_all("not-a-file")?;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let result = OpenOptions::new()
+    ///     .check_destination(true)
+    ///     .open("not-a-file");
+    /// assert!(result.is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_destination(&mut self, check_destination: bool) -> &mut Self {
+        self.inner.check_destination = check_destination;
+        self
+    }
+
+    /// Sets the option to write the contents directly to the destination file, if it cannot be
+    /// replaced atomically.
+    ///
+    /// Some destinations can be written, but not replaced: for example, files that are
+    /// bind-mounted individually (as is common in containers), for which renaming fails with
+    /// `EBUSY` on Unix, or files that are writable inside a directory that is not. By default,
+    /// opening or committing the file fails in these cases. If this option is `true`:
+    ///
+    /// * If the temporary file cannot be created in the directory of the destination (or in the
+    ///   directory set with [`temporary_dir()`](OpenOptions::temporary_dir)) because of
+    ///   insufficient permissions, it's created in the system temporary directory (see
+    ///   [`std::env::temp_dir()`]) instead, and the contents are always written in place when the
+    ///   file is committed. [`AtomicWriteFile::is_in_place()`] returns `true` for such files.
+    ///
+    /// * If renaming the temporary file fails because the destination is busy (error kind
+    ///   [`ResourceBusy`](std::io::ErrorKind::ResourceBusy)), the contents are written in place.
+    ///
+    /// Writing in place means that the destination file is overwritten with the new contents,
+    /// truncated to their length, and synced to the storage device. **This is not atomic**: other
+    /// processes may observe partially written contents, and a crash may leave the destination
+    /// corrupted. The permissions and owner of the destination are left unchanged, and the
+    /// destination must already exist. On Unix, the destination can be locked while it's written
+    /// (see [`unix::OpenOptionsExt::lock_in_place()`]).
+    ///
+    /// Because the contents have to be read back to write them in place, the file is always
+    /// opened with read access when this option is enabled. Use
+    /// [`AtomicWriteFile::commit_with_outcome()`] to find out whether the file was written in
+    /// place. The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new()
+    ///     .in_place_fallback(true)
+    ///     .open("foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// if file.commit_with_outcome()?.is_in_place() {
+    ///     eprintln!("warning: foo.txt was not written atomically");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn in_place_fallback(&mut self, in_place_fallback: bool) -> &mut Self {
+        self.in_place_fallback = in_place_fallback;
+        self
+    }
+
+    /// Sets the option to create the missing parent directories of the file.
+    ///
+    /// By default, opening a file whose directory does not exist fails with an error of kind
+    /// [`NotFound`](std::io::ErrorKind::NotFound). If this option is `true`, the directory and
+    /// all its missing ancestors are created when the file is opened, like
+    /// [`std::fs::create_dir_all()`]. Unlike `create_dir_all()`, the new directories are also made
+    /// durable: on Unix, the parent of each directory that was created is synced to the storage
+    /// device when the file is committed, so that the whole path survives a crash. On other
+    /// platforms, directories cannot be synced.
+    ///
+    /// The directories are created even if the file is later discarded. On Unix, their mode can
+    /// be set with [`unix::OpenOptionsExt::parents_mode()`]. The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new()
+    ///     .create_parents(true)
+    ///     .open("created/directories/foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn create_parents(&mut self, create_parents: bool) -> &mut Self {
+        self.inner.create_parents = create_parents;
+        self
+    }
+
+    /// Sets the option to check, when the file is committed, that its directory is still at the
+    /// path it was opened from.
+    ///
+    /// On Unix, the directory of the file is kept open, and the file is committed inside that
+    /// directory even if it's renamed, moved, or removed in the meantime (see the
+    /// [module-level documentation](crate)). In these cases, the committed file ends up in an
+    /// unexpected place, or is lost together with the removed directory. If this option is
+    /// `true`, the commit fails instead, with an error of kind
+    /// [`NotFound`](std::io::ErrorKind::NotFound) and stage [`Stage::CheckDirectory`], if the
+    /// directory was removed, or if its path no longer refers to it (comparing the device and
+    /// inode numbers). The file is discarded, unless it was committed with
+    /// [`try_commit()`](AtomicWriteFile::try_commit).
+    ///
+    /// The check is performed right before the file is renamed, but it's not atomic with the
+    /// rename: a change in that window is not detected. Relative paths are resolved against the
+    /// current working directory at commit time, except for files opened with `open_at()`
+    /// (available with the `cap-std` feature), whose paths are resolved beneath the same directory
+    /// they were opened from. Files opened with [`open_in()`](OpenOptions::open_in) are not
+    /// checked, because their directory is not resolved from a path. On platforms other than Unix,
+    /// files are always committed by path, and this option has no effect. The default is
+    /// `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let file = OpenOptions::new().check_directory(true).open("foo.txt")?;
+    /// file.commit()?; // fails if the current directory was moved or removed
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_directory(&mut self, check_directory: bool) -> &mut Self {
+        self.check_directory = check_directory;
+        self
+    }
+
+    /// Sets the prefix of the names of temporary files.
+    ///
+    /// Temporary files are named `{prefix}{filename}.{XXXXXX}{suffix}`, where `filename` is the
+    /// name of the destination file, and `XXXXXX` is a random alphanumeric string. By default,
+    /// `prefix` is `.` (so that temporary files are hidden on Unix), and `suffix` is empty.
+    ///
+    /// If the resulting name would be longer than 255 bytes, `filename` is truncated.
+    ///
+    /// The prefix cannot contain path separators; if it does, [`open()`](OpenOptions::open) will
+    /// fail with an error.
+    ///
+    /// Note that [`CleanupOptions`] recognizes temporary files by their name, so if the prefix is
+    /// customized, [`CleanupOptions::naming_from()`] must be used to find leftover temporary files.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// // The temporary file will be named like `foo.txt.XXXXXX.tmp`
+    /// let file = OpenOptions::new()
+    ///     .temporary_prefix("")
+    ///     .temporary_suffix(".tmp")
+    ///     .open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn temporary_prefix<S: AsRef<OsStr>>(&mut self, prefix: S) -> &mut Self {
+        self.inner.naming.prefix = prefix.as_ref().to_os_string();
+        self
+    }
+
+    /// Sets the suffix of the names of temporary files.
+    ///
+    /// See [`temporary_prefix()`](OpenOptions::temporary_prefix) for more details about how
+    /// temporary files are named. By default, the suffix is empty.
+    ///
+    /// The suffix cannot contain path separators; if it does, [`open()`](OpenOptions::open) will
+    /// fail with an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// // The temporary file will be named like `.foo.txt.XXXXXX.swp`
+    /// let file = OpenOptions::new().temporary_suffix(".swp").open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn temporary_suffix<S: AsRef<OsStr>>(&mut self, suffix: S) -> &mut Self {
+        self.inner.naming.suffix = suffix.as_ref().to_os_string();
+        self
+    }
+
+    /// Sets the length of the random part of the names of temporary files.
+    ///
+    /// See [`temporary_prefix()`](OpenOptions::temporary_prefix) for more details about how
+    /// temporary files are named. The default length is 6.
+    ///
+    /// Shorter lengths make collisions between concurrent writers more likely (collisions are
+    /// detected and resolved by trying a different name). The length must be greater than zero;
+    /// if it is zero, [`open()`](OpenOptions::open) will fail with an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let file = OpenOptions::new().temporary_random_len(12).open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn temporary_random_len(&mut self, len: usize) -> &mut Self {
+        self.inner.naming.random_len = len;
+        self
+    }
+
+    /// Sets the seed used to generate the random part of the names of temporary files.
+    ///
+    /// By default, a random seed is used. Using a fixed seed makes the names of temporary files
+    /// deterministic, which may be useful in tests. This should not be used in production code,
+    /// as it makes collisions between concurrent writers very likely.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let file = OpenOptions::new().temporary_seed(1234).open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn temporary_seed(&mut self, seed: u64) -> &mut Self {
+        self.inner.naming.seed = Some(seed);
+        self
+    }
+
+    /// Sets the directory where temporary files are placed.
+    ///
+    /// By default, temporary files are placed in the same directory as the destination file. If
+    /// `dir` is set, temporary files are placed in `dir` instead. If `dir` is a relative path, it
+    /// is relative to the directory of the destination file (not to the current working
+    /// directory). The directory is created if it does not exist (but its parent directories are
+    /// not).
+    ///
+    /// `dir` must be on the same filesystem as the destination file, or the
+    /// [`AtomicWriteFile`] will fail to be committed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// // The temporary file is placed in `.tmp/`, next to `foo.txt`
+    /// let file = OpenOptions::new().temporary_dir(".tmp").open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn temporary_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.inner.naming.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Removes abandoned temporary files for the same destination file when opening it.
+    ///
+    /// If set, [`open()`](OpenOptions::open) removes the temporary files left behind by previous
+    /// [`AtomicWriteFile`]s for the same path (for example, because the process was interrupted
+    /// abruptly) that were last modified at least `max_age` ago. This is equivalent to calling
+    /// [`CleanupOptions::clean_file()`] with the same `max_age` and naming settings before
+    /// opening, but it reuses the directory that is opened anyway, and it only inspects temporary
+    /// files for this specific destination file.
+    ///
+    /// Removal is best-effort: errors are ignored and do not cause `open()` to fail. See
+    /// [`CleanupOptions`] for the caveats of recognizing temporary files by their name; in
+    /// particular, `max_age` should be larger than the time it takes to write and commit a file,
+    /// or temporary files that are still in use by other processes may be removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::time::Duration;
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// // Remove temporary files for "foo.txt" that have not been modified in the last hour
+    /// let file = OpenOptions::new()
+    ///     .remove_stale_temporaries(Duration::from_secs(60 * 60))
+    ///     .open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_stale_temporaries(&mut self, max_age: Duration) -> &mut Self {
+        self.inner.remove_stale = Some(max_age);
+        self
+    }
+
     /// Opens the file at `path` with this set of options.
     ///
     /// This has the same semantics as [`std::fs::OpenOptions::open()`], except that it returns an
@@ -338,11 +891,153 @@ impl OpenOptions {
     /// ```
     pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<AtomicWriteFile> {
         let path = path.as_ref().to_path_buf();
-        let temporary_file = imp::TemporaryFile::open(&self.inner, &path)?;
-        Ok(AtomicWriteFile {
-            temporary_file,
-            finalized: false,
-        })
+        let (temporary_file, in_place) = self
+            .open_with_fallback(false, |opts| imp::TemporaryFile::open(opts, &path))
+            .map_err(|err| err.with_path(&path))?;
+        let mut file = AtomicWriteFile::new(self, temporary_file, path);
+        file.in_place = in_place;
+        Ok(file)
+    }
+
+    /// Opens the file `name` inside the directory `dir` with this set of options.
+    ///
+    /// This is similar to [`open()`](OpenOptions::open), except that the directory of the file is
+    /// not resolved from a path, but is taken from `dir`, which is kept open and can be reused to
+    /// open many files. This is similar to `openat(2)`: the resulting [`AtomicWriteFile`] refers
+    /// to `dir` even if the path it was opened from is renamed or replaced.
+    ///
+    /// `name` must be a plain file name: it cannot be empty, contain path separators, or be `.`
+    /// or `..`. Otherwise, an error of kind [`InvalidInput`](std::io::ErrorKind::InvalidInput) is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// # #[cfg(unix)]
+    /// # {
+    /// use atomic_write_file::Directory;
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let dir = Directory::open(".")?;
+    /// let mut file = OpenOptions::new().open_in(&dir, "foo.txt")?;
+    /// file.write_all(b"hello")?;
+    /// file.commit()?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_in<P: AsRef<Path>>(&self, dir: &Directory<'_>, name: P) -> Result<AtomicWriteFile> {
+        let name = plain_file_name(name.as_ref())?;
+        let (temporary_file, in_place) = self
+            .open_with_fallback(false, |opts| {
+                imp::TemporaryFile::open_in(opts, Arc::clone(&dir.inner), name)
+            })
+            .map_err(|err| err.with_path(name))?;
+        let mut file = AtomicWriteFile::new(self, temporary_file, PathBuf::from(name));
+        file.in_place = in_place;
+        // The path of `dir` is not known
+        file.check_directory = false;
+        Ok(file)
+    }
+
+    /// Opens a new file inside the directory `dir` with this set of options, without choosing its
+    /// name.
+    ///
+    /// This is useful when the name of the file is only known after its contents have been
+    /// written, for example when the name is derived from a hash of the contents. The returned
+    /// [`AtomicWriteFile`] has no destination: it must be committed with
+    /// [`commit_to()`](AtomicWriteFile::commit_to) or [`commit_as()`](AtomicWriteFile::commit_as);
+    /// [`commit()`](AtomicWriteFile::commit) and [`try_commit()`](AtomicWriteFile::try_commit)
+    /// return an error of kind [`InvalidInput`](std::io::ErrorKind::InvalidInput).
+    ///
+    /// Because the destination is not known when the file is opened, the permissions and owner of
+    /// an existing destination file are not preserved (see
+    /// [`unix::OpenOptionsExt`]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new().open_unnamed(".")?;
+    /// let contents = b"hello";
+    /// file.write_all(contents)?;
+    /// file.commit_to(format!("{}.txt", contents.len()))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_unnamed<P: AsRef<Path>>(&self, dir: P) -> Result<AtomicWriteFile> {
+        let path = dir.as_ref().to_path_buf();
+        let temporary_file = imp::TemporaryFile::open_unnamed(&self.inner, &path)
+            .map_err(|err| err.with_path(&path))?;
+        Ok(AtomicWriteFile::new(self, temporary_file, path))
+    }
+
+    /// Opens a temporary file with `open`. If the in-place fallback is enabled, and the temporary
+    /// file cannot be created because of insufficient permissions, the temporary file is opened in
+    /// the system temporary directory instead. Returns `true` if that happened.
+    ///
+    /// If the fallback fails too, the original error is returned. If `confined` is `true`, the
+    /// system temporary directory cannot be used, and an error of kind
+    /// [`Unsupported`](ErrorKind::Unsupported) is returned instead.
+    fn open_with_fallback<F>(
+        &self,
+        confined: bool,
+        open: F,
+    ) -> error::Result<(imp::TemporaryFile, bool)>
+    where
+        F: Fn(&imp::OpenOptions) -> error::Result<imp::TemporaryFile>,
+    {
+        if !self.in_place_fallback {
+            return open(&self.inner).map(|temporary_file| (temporary_file, false));
+        }
+        let mut inner = self.inner.clone();
+        inner.read = true;
+        match open(&inner) {
+            Ok(temporary_file) => Ok((temporary_file, false)),
+            Err(err)
+                if err.stage() == Stage::CreateTemporaryFile
+                    && err.kind() == ErrorKind::PermissionDenied =>
+            {
+                if confined {
+                    return Err(Error::new(
+                        Stage::CreateTemporaryFile,
+                        io::Error::new(
+                            ErrorKind::Unsupported,
+                            "permission denied, and the system temporary directory cannot be \
+                             used as a fallback outside of the capability directory",
+                        ),
+                    ));
+                }
+                inner.naming.dir = Some(std::env::temp_dir());
+                open(&inner)
+                    .map(|temporary_file| (temporary_file, true))
+                    .map_err(|_| err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Returns `name` if it's a plain file name, or an error otherwise.
+fn plain_file_name(name: &Path) -> Result<&OsStr> {
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(name),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "file name must not be empty or contain path separators",
+        )),
     }
 }
 
@@ -352,6 +1047,26 @@ impl Default for OpenOptions {
     }
 }
 
+/// How an [`AtomicWriteFile`] was committed, as returned by
+/// [`AtomicWriteFile::commit_with_outcome()`].
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CommitOutcome {
+    /// The temporary file was atomically renamed to the destination.
+    Replaced,
+    /// The destination could not be replaced, and the contents were written directly to it (see
+    /// [`OpenOptions::in_place_fallback()`]). The write was not atomic.
+    InPlace,
+}
+
+impl CommitOutcome {
+    /// Returns `true` if the contents were written in place, without atomicity guarantees.
+    #[inline]
+    pub fn is_in_place(&self) -> bool {
+        matches!(self, Self::InPlace)
+    }
+}
+
 /// A file whose contents become visible to users only after the file is committed.
 ///
 /// An `AtomicWriteFile` is a file that is assigned to a path, but whose contents won't appear at
@@ -435,10 +1150,45 @@ impl Default for OpenOptions {
 #[derive(Debug)]
 pub struct AtomicWriteFile {
     temporary_file: imp::TemporaryFile,
+    path: PathBuf,
     finalized: bool,
+    digest: Option<digest::Tracker>,
+    #[cfg(feature = "digest")]
+    record_digest: Option<DigestRecord>,
+    #[cfg(feature = "digest")]
+    sidecar_options: Option<OpenOptions>,
+    in_place_fallback: bool,
+    lock_in_place: bool,
+    in_place: bool,
+    check_directory: bool,
+    /// The directory that the path of the file is relative to, if it was opened with `open_at()`.
+    #[cfg(all(unix, feature = "cap-std"))]
+    base_dir: Option<Arc<cap_std::fs::Dir>>,
 }
 
 impl AtomicWriteFile {
+    fn new(options: &OpenOptions, temporary_file: imp::TemporaryFile, path: PathBuf) -> Self {
+        Self {
+            temporary_file,
+            path,
+            finalized: false,
+            digest: digest::Tracker::for_options(options),
+            #[cfg(feature = "digest")]
+            record_digest: options.record_digest,
+            #[cfg(feature = "digest")]
+            sidecar_options: options.sidecar_options(),
+            in_place_fallback: options.in_place_fallback,
+            #[cfg(unix)]
+            lock_in_place: options.inner.lock_in_place,
+            #[cfg(not(unix))]
+            lock_in_place: false,
+            in_place: false,
+            check_directory: options.check_directory,
+            #[cfg(all(unix, feature = "cap-std"))]
+            base_dir: None,
+        }
+    }
+
     /// Opens an atomically-written file at `path`.
     ///
     /// See [`OpenOptions`] for more details.
@@ -605,16 +1355,365 @@ impl AtomicWriteFile {
     /// ```
     #[inline]
     pub fn commit(mut self) -> Result<()> {
+        self._commit().map(drop)
+    }
+
+    /// Saves the contents of this file to its path, and reports how that was done.
+    ///
+    /// This is the same as [`commit()`](AtomicWriteFile::commit), except that it returns whether
+    /// the destination was atomically replaced, or whether the contents were written in place
+    /// because the destination could not be replaced (see
+    /// [`OpenOptions::in_place_fallback()`]). Without the in-place fallback, the outcome is always
+    /// [`CommitOutcome::Replaced`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::AtomicWriteFile;
+    /// use atomic_write_file::CommitOutcome;
+    /// use std::io::Write;
+    ///
+    /// let file = AtomicWriteFile::open("foo.txt")?;
+    /// writeln!(&file, "hello")?;
+    /// assert_eq!(file.commit_with_outcome()?, CommitOutcome::Replaced);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[inline]
+    pub fn commit_with_outcome(mut self) -> Result<CommitOutcome> {
         self._commit()
     }
 
-    fn _commit(&mut self) -> Result<()> {
+    /// Returns `true` if this file will be written in place when it's committed, because the
+    /// temporary file could not be created next to the destination (see
+    /// [`OpenOptions::in_place_fallback()`]).
+    ///
+    /// Note that even if this returns `false`, the file may still be written in place if the
+    /// destination turns out to be busy when the file is committed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    ///
+    /// let file = OpenOptions::new().in_place_fallback(true).open("foo.txt")?;
+    /// if file.is_in_place() {
+    ///     eprintln!("warning: foo.txt will not be written atomically");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[inline]
+    pub fn is_in_place(&self) -> bool {
+        self.in_place
+    }
+
+    /// Saves the contents of this file to its path, returning the file back if that fails.
+    ///
+    /// This is similar to [`commit()`](AtomicWriteFile::commit), except that if the commit
+    /// fails, the `AtomicWriteFile` is not discarded, and is returned inside the
+    /// [`CommitError`]. This makes it possible to retry the commit (for example, after the cause
+    /// of the failure has been fixed), or to recover the contents that were written.
+    ///
+    /// If the commit fails after the destination was already replaced (for example, because the
+    /// directory could not be synced after the rename: see [`Error::is_destination_replaced()`]),
+    /// the returned `AtomicWriteFile` is already committed: committing it again does nothing, and
+    /// dropping it leaves the destination unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::io::Write;
+    /// use atomic_write_file::AtomicWriteFile;
+    ///
+    /// let file = AtomicWriteFile::open("foo.txt")?;
+    /// writeln!(&file, "hello")?;
+    /// match file.try_commit() {
+    ///     Ok(()) => (),
+    ///     Err(err) => {
+    ///         eprintln!("commit failed: {}", err.error);
+    ///         // Retry once more
+    ///         err.file.commit()?;
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[allow(clippy::result_large_err)]
+    pub fn try_commit(mut self) -> std::result::Result<(), CommitError> {
+        match self._commit() {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                // If the destination was already replaced, the file cannot be committed again
+                let destination_replaced = error
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<Error>())
+                    .is_some_and(Error::is_destination_replaced);
+                if !destination_replaced {
+                    self.finalized = false;
+                }
+                Err(CommitError { error, file: self })
+            }
+        }
+    }
+
+    /// Saves the contents of this file to `name`, in the same directory as the file.
+    ///
+    /// This is similar to [`commit()`](AtomicWriteFile::commit), except that the file is renamed
+    /// to `name` instead of the path it was opened with. `name` must be a plain file name: it
+    /// cannot be empty, contain path separators, or be `.` or `..`. Otherwise, an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput) is returned.
+    ///
+    /// Note that the permissions and owner of the file (if preserved, see
+    /// [`unix::OpenOptionsExt`]) are copied from the file the
+    /// `AtomicWriteFile` was opened with, not from `name`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::io::Write;
+    /// use atomic_write_file::AtomicWriteFile;
+    ///
+    /// let file = AtomicWriteFile::open("foo.txt")?;
+    /// writeln!(&file, "hello")?;
+    /// file.commit_to("bar.txt")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn commit_to<P: AsRef<Path>>(mut self, name: P) -> Result<()> {
+        let name = plain_file_name(name.as_ref())?;
+        self.path = match self.temporary_file.name() {
+            Some(_) => self.path.with_file_name(name),
+            None => self.path.join(name),
+        };
+        self.temporary_file.set_name(name);
+        self._commit().map(drop)
+    }
+
+    /// Saves the contents of this file to `path`.
+    ///
+    /// This is similar to [`commit_to()`](AtomicWriteFile::commit_to), except that the new
+    /// destination is specified as a path. The parent directory of `path` must be the same
+    /// directory that contains the file: files cannot be moved to another directory. If `path` is
+    /// on a different filesystem, an error of kind
+    /// [`CrossesDevices`](std::io::ErrorKind::CrossesDevices) is returned; if it's on the same
+    /// filesystem, but in a different directory, an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput) is returned. In both cases, the file is
+    /// discarded.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use std::io::Write;
+    /// use atomic_write_file::AtomicWriteFile;
+    ///
+    /// let file = AtomicWriteFile::open("foo.txt")?;
+    /// writeln!(&file, "hello")?;
+    /// file.commit_as("./bar.txt")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn commit_as<P: AsRef<Path>>(mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "path does not have a file name")
+        })?;
+        let dir_path = path.parent().unwrap_or(Path::new(""));
+        self.temporary_file
+            .check_same_dir(dir_path)
+            .map_err(|err| err.with_path(path))
+            .map_err(|err| self.error(err))?;
+        self.path = path.to_path_buf();
+        self.temporary_file.set_name(name);
+        self._commit().map(drop)
+    }
+
+    /// Saves the contents of this file to `name`, inside the subdirectory `subdir` of the
+    /// directory that contains the file (creating it if necessary), unless `name` already exists.
+    ///
+    /// Returns `true` if `name` was created, or `false` if it already existed, in which case this
+    /// file is discarded.
+    #[cfg(feature = "store")]
+    pub(crate) fn commit_new_in(mut self, subdir: &OsStr, name: &OsStr) -> Result<bool> {
+        self.finalized = true;
+        self.path = match self.temporary_file.name() {
+            Some(_) => self.path.with_file_name(subdir),
+            None => self.path.join(subdir),
+        };
+        self.path.push(name);
+        self.temporary_file
+            .apply_final_mode()
+            .and_then(|()| self.sync_all().at_stage(Stage::Sync))
+            .and_then(|()| self.temporary_file.rename_file_no_replace(subdir, name))
+            .map_err(|err| self.error(err))
+    }
+
+    /// Saves the contents of this file to its path, but only if `validate` accepts them.
+    ///
+    /// After the contents have been synced to disk, and before the file is renamed to its path,
+    /// `validate` is called with the temporary file and a path that can be used to open it again
+    /// (for example, to pass it to an external program that checks the syntax of the file). If
+    /// `validate` returns an error, the file is discarded, the original file (if any) is left
+    /// unchanged, and the error is returned with [`Stage::Validate`] as its
+    /// [stage](Error::stage).
+    ///
+    /// The path passed to `validate` is not necessarily the path of the temporary file in its
+    /// directory: on Linux, it's a path under `/proc/self/fd`, which also works for files opened
+    /// without a name (see the `unnamed-tmpfile` [feature](crate#cargo-features)). On platforms
+    /// where no such path can be determined, an error of kind
+    /// [`Unsupported`](std::io::ErrorKind::Unsupported) is returned and the file is discarded.
+    ///
+    /// Note that the position of the temporary file is shared with this `AtomicWriteFile`:
+    /// `validate` should use a new file (opened from the path) or positioned reads, or rewind the
+    /// file before reading it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::AtomicWriteFile;
+    /// use std::io::Error;
+    /// use std::io::ErrorKind;
+    /// use std::io::Write;
+    ///
+    /// let mut file = AtomicWriteFile::open("foo.txt")?;
+    /// file.write_all(b"key = value\n")?;
+    /// file.commit_with_validation(|_file, path| {
+    ///     let contents = std::fs::read_to_string(path)?;
+    ///     if contents.lines().all(|line| line.contains('=')) {
+    ///         Ok(())
+    ///     } else {
+    ///         Err(Error::new(ErrorKind::InvalidData, "syntax error"))
+    ///     }
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn commit_with_validation<F>(mut self, validate: F) -> Result<()>
+    where
+        F: FnOnce(&File, &Path) -> Result<()>,
+    {
+        self._commit_with(|file| {
+            let path = file
+                .temporary_file
+                .temporary_path()
+                .map_err(|err| file.error(err))?;
+            validate(&file.temporary_file.file, &path)
+                .map_err(|err| file.error(Error::new(Stage::Validate, err)))
+        })
+        .map(drop)
+    }
+
+    #[inline]
+    fn _commit(&mut self) -> Result<CommitOutcome> {
+        self._commit_with(|_| Ok(()))
+    }
+
+    /// Syncs and renames the temporary file, calling `validate` in between. If `validate` fails,
+    /// the temporary file is removed. If the destination cannot be replaced, and the in-place
+    /// fallback is enabled, the contents are written in place instead of renaming.
+    fn _commit_with<F>(&mut self, validate: F) -> Result<CommitOutcome>
+    where
+        F: FnOnce(&Self) -> Result<()>,
+    {
         if self.finalized {
-            return Ok(());
+            return Ok(CommitOutcome::Replaced);
+        }
+        if self.temporary_file.name().is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "file was opened without a name: use commit_to() or commit_as() to commit it",
+            ));
         }
+        #[cfg(feature = "digest")]
+        let sidecar = self.record_digest()?;
         self.finalized = true;
-        self.sync_all()?;
-        self.temporary_file.rename_file()
+        self.temporary_file
+            .apply_final_mode()
+            .and_then(|()| self.sync_all().at_stage(Stage::Sync))
+            .map_err(|err| self.error(err))?;
+        if let Err(err) = validate(self) {
+            self.finalized = false;
+            self._discard()?;
+            return Err(err);
+        }
+        if let Err(err) = self.check_directory() {
+            // Let the file be discarded when dropped, or returned by `try_commit()`
+            self.finalized = false;
+            return Err(err);
+        }
+        #[cfg(feature = "digest")]
+        if let Some(sidecar) = sidecar {
+            if let Err(err) = sidecar.commit() {
+                self.finalized = false;
+                return Err(self.error(Error::new(Stage::RecordDigest, err)));
+            }
+        }
+        let outcome = if self.in_place {
+            self.write_in_place()?
+        } else {
+            match self.temporary_file.rename_file() {
+                Ok(()) => CommitOutcome::Replaced,
+                Err(ref err) if self.in_place_fallback && err.kind() == ErrorKind::ResourceBusy => {
+                    self.write_in_place()?
+                }
+                Err(err) => return Err(self.error(err)),
+            }
+        };
+        Ok(outcome)
+    }
+
+    /// Returns an error if the directory of the file is no longer at its path, if requested.
+    fn check_directory(&self) -> Result<()> {
+        if !self.check_directory {
+            return Ok(());
+        }
+        let dir_path = self.path.parent().unwrap_or(Path::new(""));
+        #[cfg(all(unix, feature = "cap-std"))]
+        if let Some(ref base_dir) = self.base_dir {
+            return self
+                .temporary_file
+                .check_dir_unchanged_at(base_dir, dir_path)
+                .map_err(|err| self.error(err));
+        }
+        self.temporary_file
+            .check_dir_unchanged(dir_path)
+            .map_err(|err| self.error(err))
+    }
+
+    /// Writes the contents of the temporary file directly to the destination file, and removes
+    /// the temporary file.
+    fn write_in_place(&mut self) -> Result<CommitOutcome> {
+        self.temporary_file
+            .write_in_place(self.lock_in_place)
+            .map_err(|err| self.error(err))?;
+        self.temporary_file
+            .remove_file()
+            .map_err(|err| self.error(err.with_destination_replaced(true)))?;
+        Ok(CommitOutcome::InPlace)
     }
 
     /// Discard the contents of this file, and leave its path unchanged.
@@ -653,7 +1752,30 @@ impl AtomicWriteFile {
             return Ok(());
         }
         self.finalized = true;
-        self.temporary_file.remove_file()
+        self.temporary_file
+            .remove_file()
+            .map_err(|err| self.error(err))
+    }
+
+    /// Performs `write` on the underlying file, updating the digest (if enabled) with the data
+    /// that was written. See [`digest::Tracker::write()`] for the meaning of the arguments.
+    #[inline]
+    fn tracked_write<F>(&self, offset: Option<u64>, data: &[IoSlice<'_>], write: F) -> Result<usize>
+    where
+        F: FnOnce(&File) -> Result<usize>,
+    {
+        match self.digest {
+            Some(ref digest) => digest.write(&self.temporary_file.file, offset, data, write),
+            None => write(&self.temporary_file.file),
+        }
+    }
+
+    /// Adds information about this file to `err`.
+    fn error(&self, err: Error) -> io::Error {
+        err.with_path(&self.path)
+            .with_temporary_name(self.temporary_file.temporary_name())
+            .with_destination_replaced(self.temporary_file.is_renamed())
+            .into()
     }
 }
 
@@ -774,7 +1896,7 @@ impl Read for &AtomicWriteFile {
 impl Write for AtomicWriteFile {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.temporary_file.file.write(buf)
+        (&*self).write(buf)
     }
 
     #[inline]
@@ -784,7 +1906,7 @@ impl Write for AtomicWriteFile {
 
     #[inline]
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
-        self.temporary_file.file.write_vectored(bufs)
+        (&*self).write_vectored(bufs)
     }
 
     #[inline]
@@ -795,25 +1917,25 @@ impl Write for AtomicWriteFile {
 
     #[inline]
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
-        self.temporary_file.file.write_all(buf)
+        (&*self).write_all(buf)
     }
 
     #[inline]
     #[cfg(feature = "unstable-write_all_vectored")]
     fn write_all_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> Result<()> {
-        self.temporary_file.file.write_all_vectored(bufs)
+        (&*self).write_all_vectored(bufs)
     }
 
     #[inline]
     fn write_fmt(&mut self, fmt: Arguments<'_>) -> Result<()> {
-        self.temporary_file.file.write_fmt(fmt)
+        (&*self).write_fmt(fmt)
     }
 }
 
 impl Write for &AtomicWriteFile {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        (&self.temporary_file.file).write(buf)
+        self.tracked_write(None, &[IoSlice::new(buf)], |mut file| file.write(buf))
     }
 
     #[inline]
@@ -823,7 +1945,7 @@ impl Write for &AtomicWriteFile {
 
     #[inline]
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
-        (&self.temporary_file.file).write_vectored(bufs)
+        self.tracked_write(None, bufs, |mut file| file.write_vectored(bufs))
     }
 
     #[inline]
@@ -834,18 +1956,31 @@ impl Write for &AtomicWriteFile {
 
     #[inline]
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
-        (&self.temporary_file.file).write_all(buf)
+        self.tracked_write(None, &[IoSlice::new(buf)], |mut file| {
+            file.write_all(buf).map(|()| buf.len())
+        })?;
+        Ok(())
     }
 
     #[inline]
     #[cfg(feature = "unstable-write_all_vectored")]
     fn write_all_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> Result<()> {
-        (&self.temporary_file.file).write_all_vectored(bufs)
+        // `write_all_vectored()` modifies `bufs`, so a copy is needed to update the digest
+        let data = bufs.to_vec();
+        let len = data.iter().map(|buf| buf.len()).sum();
+        self.tracked_write(None, &data, |mut file| {
+            file.write_all_vectored(bufs).map(|()| len)
+        })?;
+        Ok(())
     }
 
     #[inline]
     fn write_fmt(&mut self, fmt: Arguments<'_>) -> Result<()> {
-        (&self.temporary_file.file).write_fmt(fmt)
+        match self.digest {
+            // The formatted data is needed to update the digest
+            Some(_) => self.write_all(fmt.to_string().as_bytes()),
+            None => (&self.temporary_file.file).write_fmt(fmt),
+        }
     }
 }
 
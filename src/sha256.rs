@@ -0,0 +1,53 @@
+//! SHA-256 hashing, shared by the `store` and `digest` features.
+
+use sha2::Digest;
+use std::fmt::Write as _;
+use std::io;
+
+/// Length of a SHA-256 digest, in bytes.
+pub(crate) const DIGEST_LEN: usize = 32;
+
+/// Incremental SHA-256 hasher.
+///
+/// Data can be added with [`update()`](Hasher::update), or by writing to the hasher (for example
+/// with [`io::copy()`]).
+#[derive(Clone, Debug)]
+pub(crate) struct Hasher(sha2::Sha256);
+
+impl Hasher {
+    pub(crate) fn new() -> Self {
+        Self(sha2::Sha256::new())
+    }
+
+    #[inline]
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Returns the digest of all the data hashed so far.
+    pub(crate) fn finalize(self) -> [u8; DIGEST_LEN] {
+        self.0.finalize().into()
+    }
+}
+
+impl io::Write for Hasher {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns `digest` as a lowercase hexadecimal string.
+pub(crate) fn to_hex(digest: &[u8; DIGEST_LEN]) -> String {
+    let mut hex = String::with_capacity(2 * DIGEST_LEN);
+    for byte in digest {
+        write!(hex, "{byte:02x}").expect("write to String failed");
+    }
+    hex
+}
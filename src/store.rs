@@ -0,0 +1,197 @@
+//! A content-addressed store of files, built on top of [`AtomicWriteFile`].
+//!
+//! A [`Store`] is a directory that contains files (blobs) named after the SHA-256 hash of their
+//! contents. A blob with hash `hash` (in lowercase hexadecimal) is stored at
+//! `<dir>/<hash[..2]>/<hash>`, where `<dir>` is the directory of the store, and `<hash[..2]>` is
+//! a subdirectory named after the first two characters of the hash, which keeps the number of
+//! entries per directory small.
+//!
+//! Blobs are written through a [`BlobWriter`], which computes the hash while the contents are
+//! written to an [`AtomicWriteFile`], and then commits the file under its hash. Because the
+//! name of a blob is determined by its contents, blobs are never replaced: if a blob with the same
+//! hash already exists, the new file is discarded and the existing blob is kept. This makes it
+//! safe for multiple threads or processes to insert the same contents concurrently.
+//!
+//! # Examples
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+//! # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+//! # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+//! # std::fs::create_dir_all("blobs")?;
+//! use atomic_write_file::store::Store;
+//! use std::io::Write;
+//!
+//! let store = Store::new("blobs");
+//! let mut writer = store.writer()?;
+//! writer.write_all(b"hello")?;
+//! let hash = writer.commit()?;
+//! assert_eq!(
+//!     hash,
+//!     "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+//! );
+//! assert_eq!(std::fs::read(store.path(&hash)?)?, b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::AtomicWriteFile;
+use crate::OpenOptions;
+use crate::sha256;
+use crate::sha256::Hasher;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Length of a SHA-256 hash, in hexadecimal characters.
+const HASH_LEN: usize = 2 * sha256::DIGEST_LEN;
+
+/// Length of the prefix of the hash used to name subdirectories.
+const PREFIX_LEN: usize = 2;
+
+/// A content-addressed store of files.
+///
+/// See the [module-level documentation](self) for more details.
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+    options: OpenOptions,
+}
+
+impl Store {
+    /// Returns a store for the directory `dir`, using the default [`OpenOptions`] to write blobs.
+    ///
+    /// This method does not access the filesystem. The directory must exist before blobs can be
+    /// written to it; subdirectories are created as needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self::with_options(dir, OpenOptions::new())
+    }
+
+    /// Returns a store for the directory `dir`, using `options` to write blobs.
+    ///
+    /// `options` can be used to customize how temporary files are named and placed, and (on Unix)
+    /// their permissions. Temporary files are created in `dir` (or in the
+    /// [`temporary_dir()`](OpenOptions::temporary_dir) relative to `dir`), and are then moved to
+    /// their subdirectory when committed.
+    pub fn with_options<P: AsRef<Path>>(dir: P, options: OpenOptions) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            options,
+        }
+    }
+
+    /// Returns the directory of this store.
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the path of the blob with the given hash.
+    ///
+    /// The blob may not exist. Returns an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput) if `hash` is not a SHA-256 hash in
+    /// lowercase hexadecimal form.
+    pub fn path(&self, hash: &str) -> Result<PathBuf> {
+        let (prefix, name) = split_hash(hash)?;
+        Ok(self.dir.join(prefix).join(name))
+    }
+
+    /// Returns `true` if the blob with the given hash exists.
+    pub fn contains(&self, hash: &str) -> Result<bool> {
+        self.path(hash)?.try_exists()
+    }
+
+    /// Opens the blob with the given hash for reading.
+    pub fn open(&self, hash: &str) -> Result<File> {
+        File::open(self.path(hash)?)
+    }
+
+    /// Returns a new [`BlobWriter`] that can be used to add a blob to this store.
+    pub fn writer(&self) -> Result<BlobWriter> {
+        Ok(BlobWriter {
+            file: self.options.open_unnamed(&self.dir)?,
+            hasher: Hasher::new(),
+        })
+    }
+
+    /// Adds a blob with the given contents to this store, and returns its hash.
+    ///
+    /// This is a shorthand for [`writer()`](Store::writer), followed by
+    /// [`write_all()`](Write::write_all) and [`commit()`](BlobWriter::commit).
+    pub fn insert(&self, contents: &[u8]) -> Result<String> {
+        let mut writer = self.writer()?;
+        writer.write_all(contents)?;
+        writer.commit()
+    }
+}
+
+/// Splits `hash` into the name of its subdirectory and the name of its file.
+fn split_hash(hash: &str) -> Result<(&OsStr, &OsStr)> {
+    let valid = hash.len() == HASH_LEN
+        && hash
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c));
+    if !valid {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "hash must be a SHA-256 hash in lowercase hexadecimal form",
+        ));
+    }
+    Ok((OsStr::new(&hash[..PREFIX_LEN]), OsStr::new(hash)))
+}
+
+/// A writer for a new blob in a [`Store`].
+///
+/// The contents written to a `BlobWriter` are hashed as they're written, and are stored in an
+/// [`AtomicWriteFile`]. Calling [`commit()`](BlobWriter::commit) moves the file to its final
+/// path in the store. If the `BlobWriter` is dropped without being committed, the contents are
+/// discarded.
+///
+/// Unlike [`AtomicWriteFile`], `BlobWriter` does not give access to the underlying file, and
+/// does not implement [`Seek`](std::io::Seek), so that the hash always matches the contents.
+#[derive(Debug)]
+pub struct BlobWriter {
+    file: AtomicWriteFile,
+    hasher: Hasher,
+}
+
+impl BlobWriter {
+    /// Adds the blob to the store, and returns its hash in lowercase hexadecimal form.
+    ///
+    /// The blob is stored at [`Store::path()`]. If a blob with the same hash already exists in
+    /// the store, it is left untouched, the contents written to this `BlobWriter` are discarded,
+    /// and this method succeeds.
+    pub fn commit(self) -> Result<String> {
+        let hash = sha256::to_hex(&self.hasher.finalize());
+        let (prefix, name) = split_hash(&hash)?;
+        self.file.commit_new_in(prefix, name)?;
+        Ok(hash)
+    }
+
+    /// Discards the contents written to this `BlobWriter`.
+    ///
+    /// This is equivalent to dropping the `BlobWriter`, except that errors are reported.
+    #[inline]
+    pub fn discard(self) -> Result<()> {
+        self.file.discard()
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
@@ -2,6 +2,11 @@
 #![allow(clippy::print_stdout)]
 
 use crate::AtomicWriteFile;
+use crate::CleanupOptions;
+use crate::Directory;
+use crate::Error;
+use crate::OpenOptions;
+use crate::Stage;
 use std::fs;
 use std::io::ErrorKind;
 use std::io::Read;
@@ -12,6 +17,7 @@ use std::io::Write;
 use std::panic;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 fn test_directory() -> PathBuf {
     let path = option_env!("TEST_DIR").unwrap_or("target/test-files");
@@ -32,6 +38,19 @@ fn test_file<P: AsRef<Path>>(name: P) -> PathBuf {
     path
 }
 
+fn test_subdirectory<P: AsRef<Path>>(name: P) -> PathBuf {
+    let mut path = test_directory();
+    path.push(name);
+    match fs::remove_dir_all(&path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        Err(ref err) => panic!("failed to remove test subdirectory {path:?}: {err}"),
+    }
+    fs::create_dir(&path)
+        .unwrap_or_else(|err| panic!("failed to create test subdirectory {path:?}: {err}"));
+    path
+}
+
 fn list_temporary_files<P: AsRef<Path>>(path: P) -> impl Iterator<Item = PathBuf> {
     let path = path.as_ref();
     let dir_path = path.parent().unwrap();
@@ -72,6 +91,12 @@ fn verify_temporary_file_name<P1: AsRef<Path>, P2: AsRef<Path>>(
     );
 }
 
+fn error_details(err: &std::io::Error) -> &Error {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<Error>())
+        .unwrap_or_else(|| panic!("error has no details: {err:?}"))
+}
+
 fn verify_no_leftovers<P: AsRef<Path>>(path: P) {
     let leftovers = list_temporary_files(path).collect::<Vec<PathBuf>>();
     if !leftovers.is_empty() {
@@ -241,6 +266,402 @@ fn supports_directory_fd() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn errors_have_details() -> Result<()> {
+    let dir = test_subdirectory("error-details");
+
+    let path = dir.join("missing").join("foo");
+    let err = AtomicWriteFile::open(&path).expect_err("open should fail");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let details = error_details(&err);
+    assert_eq!(details.stage(), Stage::OpenDirectory);
+    assert_eq!(details.path(), Some(path.as_path()));
+    assert_eq!(details.temporary_name(), None);
+
+    // Renaming a file over a non-empty directory fails
+    let path = dir.join("foo");
+    fs::create_dir(&path)?;
+    fs::write(path.join("bar"), b"")?;
+    let file = AtomicWriteFile::open(&path)?;
+    let temporary_name = list_temporary_files(&path)
+        .next()
+        .map(|path| path.file_name().unwrap().to_os_string());
+    let err = file.commit().expect_err("commit should fail");
+    let details = error_details(&err);
+    assert_eq!(details.stage(), Stage::Rename);
+    assert_eq!(details.path(), Some(path.as_path()));
+    assert!(details.temporary_name().is_some());
+    if temporary_name.is_some() {
+        assert_eq!(details.temporary_name(), temporary_name.as_deref());
+    }
+    assert_eq!(details.kind(), err.kind());
+    let message = err.to_string();
+    assert!(
+        message.contains("failed to rename temporary file"),
+        "{message}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn try_commit_returns_file_on_failure() -> Result<()> {
+    let dir = test_subdirectory("try-commit");
+    let path = dir.join("foo");
+    fs::create_dir(&path)?;
+    fs::write(path.join("bar"), b"")?;
+
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"hello\n")?;
+    let err = file.try_commit().expect_err("commit should fail");
+    assert_eq!(error_details(&err.error).stage(), Stage::Rename);
+
+    // The file is still usable after the failure
+    let mut file = err.file;
+    file.rewind()?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    assert_eq!(contents, b"hello\n");
+
+    fs::remove_dir_all(&path)?;
+    file.try_commit().expect("commit should succeed");
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    verify_no_leftovers(&path);
+
+    // Dropping the file after a failure removes the temporary file
+    let path = dir.join("baz");
+    fs::create_dir(&path)?;
+    fs::write(path.join("bar"), b"")?;
+    let file = AtomicWriteFile::open(&path)?;
+    let err = file.try_commit().expect_err("commit should fail");
+    drop(err);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+fn commit_error_source() -> Result<()> {
+    use std::error::Error as _;
+
+    let dir = test_subdirectory("commit-error-source");
+    let path = dir.join("foo");
+    fs::create_dir(&path)?;
+    fs::write(path.join("bar"), b"")?;
+
+    let file = AtomicWriteFile::open(&path)?;
+    let err = file.try_commit().expect_err("commit should fail");
+
+    // The details of the error can be found by walking the chain of sources
+    let mut source = err.source();
+    let details = loop {
+        let current = source.expect("error chain has no details");
+        let details = current.downcast_ref::<Error>().or_else(|| {
+            current
+                .downcast_ref::<std::io::Error>()
+                .and_then(|err| err.get_ref())
+                .and_then(|err| err.downcast_ref::<Error>())
+        });
+        if let Some(details) = details {
+            break details;
+        }
+        source = current.source();
+    };
+    assert_eq!(details.stage(), Stage::Rename);
+    assert_eq!(details.path(), Some(path.as_path()));
+    drop(err);
+
+    verify_no_leftovers(&path);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn try_commit_after_failed_directory_sync() -> Result<()> {
+    use crate::imp::FAIL_SYNC_DIR;
+
+    let path = test_file("try-commit-sync-dir");
+    let mut file = AtomicWriteFile::open(&path)?;
+    file.write_all(b"hello\n")?;
+    FAIL_SYNC_DIR.set(Some(0));
+    let err = file.try_commit().expect_err("commit should fail");
+    let details = error_details(&err.error);
+    assert_eq!(details.stage(), Stage::SyncDirectory);
+    assert!(details.is_destination_replaced());
+    assert_eq!(fs::read(&path)?, b"hello\n");
+
+    // The file was already committed: committing it again does nothing
+    err.file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    verify_no_leftovers(&path);
+    fs::remove_file(&path)
+}
+
+#[test]
+#[cfg(unix)]
+fn try_commit_after_failed_parent_sync() -> Result<()> {
+    use crate::imp::FAIL_SYNC_DIR;
+
+    let dir = test_subdirectory("try-commit-sync-parents");
+    let path = dir.join("new").join("foo");
+    let mut file = OpenOptions::new().create_parents(true).open(&path)?;
+    file.write_all(b"hello\n")?;
+    // Syncing the directory of the file succeeds, syncing the parent of `new` fails
+    FAIL_SYNC_DIR.set(Some(1));
+    let err = file.try_commit().expect_err("commit should fail");
+    let details = error_details(&err.error);
+    assert_eq!(details.stage(), Stage::SyncDirectory);
+    assert!(details.is_destination_replaced());
+
+    // Dropping the file leaves the destination unchanged
+    drop(err);
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    verify_no_leftovers(&path);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn try_commit_after_failed_sidecar() -> Result<()> {
+    use crate::DigestRecord;
+
+    let dir = test_subdirectory("try-commit-sidecar");
+    let path = dir.join("foo");
+    let sidecar_path = dir.join("foo.sha256");
+    // A directory in place of the sidecar file prevents the sidecar from being committed
+    fs::create_dir(&sidecar_path)?;
+    fs::write(sidecar_path.join("bar"), b"")?;
+
+    let mut file = OpenOptions::new()
+        .compute_digest(true)
+        .record_digest(Some(DigestRecord::Sidecar))
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    let err = file.try_commit().expect_err("commit should fail");
+    let details = error_details(&err.error);
+    assert_eq!(details.stage(), Stage::RecordDigest);
+    assert!(!details.is_destination_replaced());
+    // The sidecar is committed before the file, so the file is left unchanged
+    assert!(!path.exists());
+
+    fs::remove_dir_all(&sidecar_path)?;
+    err.file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    assert!(DigestRecord::Sidecar.verify(&path)?);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+#[cfg(unix)]
+fn sidecar_uses_same_options() -> Result<()> {
+    use crate::DigestRecord;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = test_file("sidecar-options");
+    let sidecar_path = test_file("sidecar-options.sha256");
+    let mut file = OpenOptions::new()
+        .compute_digest(true)
+        .record_digest(Some(DigestRecord::Sidecar))
+        .permissions(fs::Permissions::from_mode(0o640))
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+
+    let mode = fs::metadata(&sidecar_path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    verify_no_leftovers(&sidecar_path);
+    Ok(())
+}
+
+fn list_directory<P: AsRef<Path>>(path: P) -> Result<Vec<std::ffi::OsString>> {
+    let mut names = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn commit_to_other_names() -> Result<()> {
+    let dir = test_subdirectory("commit-to");
+
+    let file = AtomicWriteFile::open(dir.join("foo"))?;
+    (&file).write_all(b"hello\n")?;
+    file.commit_to("bar")?;
+    assert_eq!(fs::read(dir.join("bar"))?, b"hello\n");
+
+    let file = AtomicWriteFile::open(dir.join("foo"))?;
+    (&file).write_all(b"world\n")?;
+    file.commit_as(dir.join(".").join("baz"))?;
+    assert_eq!(fs::read(dir.join("baz"))?, b"world\n");
+
+    let file = AtomicWriteFile::open(dir.join("foo"))?;
+    let err = file.commit_to("../foo").expect_err("commit should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let subdir = dir.join("subdir");
+    fs::create_dir(&subdir)?;
+    let file = AtomicWriteFile::open(dir.join("foo"))?;
+    let err = file
+        .commit_as(subdir.join("foo"))
+        .expect_err("commit should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(error_details(&err).stage(), Stage::Rename);
+
+    assert_eq!(list_directory(&dir)?, ["bar", "baz", "subdir"]);
+    assert_eq!(list_directory(&subdir)?, [] as [&str; 0]);
+
+    Ok(())
+}
+
+#[test]
+fn open_unnamed() -> Result<()> {
+    let dir = test_subdirectory("open-unnamed");
+
+    let file = OpenOptions::new().open_unnamed(&dir)?;
+    (&file).write_all(b"hello\n")?;
+    let err = file.try_commit().expect_err("commit should fail");
+    assert_eq!(err.error.kind(), ErrorKind::InvalidInput);
+    err.file.commit_to("foo")?;
+    assert_eq!(fs::read(dir.join("foo"))?, b"hello\n");
+
+    let file = OpenOptions::new().open_unnamed(&dir)?;
+    (&file).write_all(b"world\n")?;
+    file.commit_as(dir.join("bar"))?;
+    assert_eq!(fs::read(dir.join("bar"))?, b"world\n");
+
+    let file = OpenOptions::new().open_unnamed(&dir)?;
+    file.discard()?;
+
+    assert_eq!(list_directory(&dir)?, ["bar", "foo"]);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn open_in_directory() -> Result<()> {
+    let dir_path = test_subdirectory("open-in");
+    let dir = Directory::open(&dir_path)?;
+    let options = OpenOptions::new();
+
+    for name in ["../foo", "foo/bar", "", ".", ".."] {
+        let err = options
+            .open_in(&dir, name)
+            .expect_err("open_in should fail");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "name: {name:?}");
+    }
+
+    let mut file = options.open_in(&dir, "foo")?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(dir_path.join("foo"))?, b"hello\n");
+
+    // The directory is not resolved again from its path
+    let renamed_path = test_subdirectory("open-in-renamed");
+    fs::remove_dir(&renamed_path)?;
+    fs::rename(&dir_path, &renamed_path)?;
+    let mut file = options.open_in(&dir, "bar")?;
+    file.write_all(b"world\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(renamed_path.join("bar"))?, b"world\n");
+    verify_no_leftovers(renamed_path.join("bar"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn shares_cached_directories() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::fd::AsRawFd;
+
+    let dir_path = test_subdirectory("cached-dir");
+    let mut options = OpenOptions::new();
+    options.cache_directories(true);
+
+    let foo = options.open(dir_path.join("foo"))?;
+    let bar = options.open(dir_path.join("bar"))?;
+    let foo_dir = foo.directory().expect("no directory descriptor returned");
+    let bar_dir = bar.directory().expect("no directory descriptor returned");
+    assert_eq!(foo_dir.as_raw_fd(), bar_dir.as_raw_fd());
+
+    // Replacing the directory invalidates the cached descriptor
+    let old_path = test_subdirectory("cached-dir-old");
+    fs::remove_dir(&old_path)?;
+    fs::rename(&dir_path, &old_path)?;
+    fs::create_dir(&dir_path)?;
+    let baz = options.open(dir_path.join("baz"))?;
+    let baz_dir = baz.directory().expect("no directory descriptor returned");
+    assert_ne!(foo_dir.as_raw_fd(), baz_dir.as_raw_fd());
+
+    foo.commit()?;
+    bar.commit()?;
+    baz.commit()?;
+    assert!(old_path.join("foo").exists());
+    assert!(old_path.join("bar").exists());
+    assert!(dir_path.join("baz").exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn secure_resolution_rejects_symlinks() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::symlink;
+
+    let dir_path = test_subdirectory("secure-resolution");
+    fs::create_dir(dir_path.join("real"))?;
+    symlink("real", dir_path.join("link"))?;
+    let mut options = OpenOptions::new();
+    options.secure_resolution(true);
+
+    let file = options.open(dir_path.join("real").join("foo"))?;
+    file.commit()?;
+    assert!(dir_path.join("real").join("foo").exists());
+
+    let err = options
+        .open(dir_path.join("link").join("bar"))
+        .expect_err("symlink should not be followed");
+    let details = error_details(&err);
+    assert_eq!(details.stage(), Stage::OpenDirectory);
+    assert_eq!(details.raw_os_error(), Some(nix::libc::ELOOP));
+
+    let err = options
+        .clone()
+        .temporary_dir("link")
+        .open(dir_path.join("baz"))
+        .expect_err("symlink should not be followed");
+    let details = error_details(&err);
+    assert_eq!(details.stage(), Stage::OpenTemporaryDirectory);
+    assert_eq!(details.raw_os_error(), Some(nix::libc::ELOOP));
+
+    // Sticky directories owned by other users are rejected; changing the owner requires root
+    if nix::unistd::Uid::effective().is_root() {
+        use std::os::unix::fs::PermissionsExt;
+        let sticky_path = dir_path.join("sticky");
+        fs::create_dir(&sticky_path)?;
+        fs::set_permissions(&sticky_path, fs::Permissions::from_mode(0o1777))?;
+        std::os::unix::fs::chown(&sticky_path, Some(12345), None)?;
+        let err = options
+            .open(sticky_path.join("foo"))
+            .expect_err("foreign sticky directory should be rejected");
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        OpenOptions::new().open(sticky_path.join("foo"))?.commit()?;
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(not(unix))]
+fn doesnt_support_opening_directories() {
+    let err = Directory::open(test_directory()).expect_err("Directory::open should fail");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
 #[test]
 #[cfg(not(unix))]
 fn doesnt_support_directory_fd() -> Result<()> {
@@ -252,3 +673,1435 @@ fn doesnt_support_directory_fd() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn cleanup_removes_leftovers() -> Result<()> {
+    let dir = test_subdirectory("cleanup");
+    fs::write(dir.join("foo"), b"contents")?;
+    fs::write(dir.join(".foo.aB3dE6"), b"leftover")?;
+    fs::write(dir.join("bar"), b"contents")?;
+    fs::write(dir.join(".bar.123456"), b"leftover")?;
+    fs::write(dir.join(".foo.backup~"), b"not a temporary file")?;
+    fs::create_dir(dir.join(".baz.abcdef"))?;
+
+    // Files are removed only if old enough
+    let removed = CleanupOptions::new().clean_dir(&dir)?;
+    assert_eq!(removed, [] as [PathBuf; 0]);
+
+    let mut options = CleanupOptions::new();
+    options.max_age(Duration::ZERO);
+    let removed = options.clean_file(dir.join("foo"))?;
+    assert_eq!(removed, [dir.join(".foo.aB3dE6")]);
+    assert!(dir.join(".bar.123456").exists());
+
+    let removed = options.clean_dir(&dir)?;
+    assert_eq!(removed, [dir.join(".bar.123456")]);
+
+    assert_eq!(fs::read(dir.join("foo"))?, b"contents");
+    assert!(dir.join(".foo.backup~").exists());
+    assert!(dir.join(".baz.abcdef").is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn cleanup_dir_keeps_unrelated_files() -> Result<()> {
+    let dir = test_subdirectory("cleanup-unrelated");
+    // These follow the naming scheme, but their destination files do not exist
+    fs::write(dir.join(".env.sample"), b"not a temporary file")?;
+    fs::write(dir.join(".env.sample1"), b"not a temporary file")?;
+    fs::write(dir.join(".config.backup"), b"not a temporary file")?;
+    // This embeds the ID of a process that is not running
+    fs::write(dir.join(".gone.abcdef.2147483647-0"), b"leftover")?;
+
+    let removed = CleanupOptions::new()
+        .max_age(Duration::ZERO)
+        .clean_dir(&dir)?;
+    assert_eq!(removed, [dir.join(".gone.abcdef.2147483647-0")]);
+    assert!(dir.join(".env.sample").exists());
+    assert!(dir.join(".env.sample1").exists());
+    assert!(dir.join(".config.backup").exists());
+
+    // Temporary files for a specific destination are still removed
+    let removed = CleanupOptions::new()
+        .max_age(Duration::ZERO)
+        .clean_file(dir.join("config"))?;
+    assert_eq!(removed, [dir.join(".config.backup")]);
+
+    Ok(())
+}
+
+#[test]
+fn cleanup_honors_max_age() -> Result<()> {
+    let dir = test_subdirectory("cleanup-max-age");
+    let path = dir.join("foo");
+    fs::write(dir.join(".foo.abcdef"), b"leftover")?;
+
+    let removed = CleanupOptions::new()
+        .max_age(Duration::from_secs(60 * 60))
+        .clean_file(&path)?;
+    assert_eq!(removed, [] as [PathBuf; 0]);
+
+    let removed = CleanupOptions::new()
+        .max_age(Duration::ZERO)
+        .clean_file(&path)?;
+    assert_eq!(removed, [dir.join(".foo.abcdef")]);
+
+    Ok(())
+}
+
+#[test]
+fn open_removes_stale_temporaries() -> Result<()> {
+    let dir = test_subdirectory("open-removes-stale");
+    let path = dir.join("foo");
+    fs::write(dir.join(".foo.abcdef"), b"leftover")?;
+    fs::write(dir.join(".bar.abcdef"), b"leftover")?;
+
+    let file = OpenOptions::new()
+        .remove_stale_temporaries(Duration::from_secs(60 * 60))
+        .open(&path)?;
+    assert!(dir.join(".foo.abcdef").exists());
+    file.discard()?;
+
+    let mut file = OpenOptions::new()
+        .remove_stale_temporaries(Duration::ZERO)
+        .open(&path)?;
+    assert!(!dir.join(".foo.abcdef").exists());
+    assert!(dir.join(".bar.abcdef").exists());
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn cleanup_keeps_temporary_files_of_running_processes() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+
+    let dir = test_subdirectory("cleanup-pid");
+    let path = dir.join("foo");
+    let file = AtomicWriteFile::options()
+        .pid_in_temporary_name(true)
+        .open(&path)?;
+
+    // Unnamed temporary files are only given a name when they're committed: make the commit fail
+    // after that, by placing a directory at the destination, so that the name can be checked
+    fs::create_dir(&path)?;
+    let err = file.try_commit().expect_err("commit should fail");
+    let file = err.file;
+    fs::remove_dir(&path)?;
+
+    let temp_file_name = list_temporary_files(&path)
+        .next()
+        .expect("no temporary files found");
+    let temp_file_name = temp_file_name.to_str().unwrap();
+    let pid_suffix = format!(".{}-", std::process::id());
+    assert!(
+        temp_file_name.contains(&pid_suffix),
+        "temporary file name does not contain the process ID: {temp_file_name:?}"
+    );
+
+    // Process IDs are never greater than 2^22 on Linux, and are `i32` on all Unix platforms
+    fs::write(dir.join(".foo.abcdef.2147483647-0"), b"leftover")?;
+
+    // The process may just not be visible (for example, it may be in another PID namespace), so
+    // the age of the temporary file is still checked
+    let removed = CleanupOptions::new().clean_file(&path)?;
+    assert_eq!(removed, [] as [PathBuf; 0]);
+
+    let removed = CleanupOptions::new()
+        .max_age(Duration::ZERO)
+        .clean_file(&path)?;
+    assert_eq!(removed, [dir.join(".foo.abcdef.2147483647-0")]);
+
+    file.commit()?;
+    assert!(path.exists());
+    verify_no_leftovers(path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(not(all(target_os = "linux", feature = "unnamed-tmpfile")))]
+fn custom_temporary_names() -> Result<()> {
+    let dir = test_subdirectory("custom-names");
+    let path = dir.join("foo");
+    let mut options = OpenOptions::new();
+    options
+        .temporary_prefix("")
+        .temporary_suffix(".tmp")
+        .temporary_random_len(10)
+        .temporary_seed(1234);
+
+    let file1 = options.open(&path)?;
+    let file2 = options.open(&path)?;
+
+    let mut names = fs::read_dir(&dir)?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(names.len(), 2, "unexpected temporary files: {names:?}");
+    for name in &names {
+        let random = name
+            .strip_prefix("foo.")
+            .and_then(|name| name.strip_suffix(".tmp"))
+            .unwrap_or_else(|| panic!("invalid temporary file name: {name:?}"));
+        assert!(random.len() == 10 && random.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    // The same seed generates the same names
+    file1.discard()?;
+    file2.discard()?;
+    let file1 = options.open(&path)?;
+    let file2 = options.open(&path)?;
+    let mut new_names = fs::read_dir(&dir)?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    new_names.sort();
+    assert_eq!(names, new_names);
+
+    // Temporary files can be found only using the same naming options
+    let removed = CleanupOptions::new()
+        .max_age(Duration::ZERO)
+        .clean_file(&path)?;
+    assert_eq!(removed, [] as [PathBuf; 0]);
+    let mut removed = CleanupOptions::new()
+        .naming_from(&options)
+        .max_age(Duration::ZERO)
+        .clean_file(&path)?;
+    removed.sort();
+    assert_eq!(removed, [dir.join(&names[0]), dir.join(&names[1])]);
+
+    // The files were removed by the cleanup, so they cannot be committed anymore
+    assert!(file1.commit().is_err());
+    assert!(file2.commit().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn custom_temporary_dir() -> Result<()> {
+    let dir = test_subdirectory("custom-dir");
+    let path = dir.join("foo");
+    let mut file = OpenOptions::new().temporary_dir("staging").open(&path)?;
+    file.write_all(b"hello\n")?;
+    assert!(dir.join("staging").is_dir());
+    assert!(!path.exists());
+
+    file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    assert_eq!(fs::read_dir(dir.join("staging"))?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn truncates_long_names() -> Result<()> {
+    let name = "a".repeat(250);
+    let path = test_file(&name);
+    let mut file = AtomicWriteFile::open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    verify_no_leftovers(&path);
+    fs::remove_file(&path)
+}
+
+#[test]
+#[cfg(unix)]
+fn truncates_long_non_utf8_names() -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let dir_path = test_subdirectory("non-utf8-names");
+    let path = dir_path.join(OsStr::from_bytes(&[0xff; 250]));
+    let mut file = AtomicWriteFile::open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    let entries = fs::read_dir(&dir_path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    assert_eq!(entries, [path]);
+    Ok(())
+}
+
+/// Runs the test `name` again in a child process, with the environment variable
+/// `ATOMIC_WRITE_FILE_TEST_CHILD` set. Used for tests that need to terminate the process.
+#[cfg(all(unix, not(all(target_os = "linux", feature = "unnamed-tmpfile"))))]
+fn run_in_child_process(name: &str) -> Result<std::process::ExitStatus> {
+    std::process::Command::new(std::env::current_exe()?)
+        .args(["--exact", name, "--nocapture", "--test-threads=1"])
+        .env("ATOMIC_WRITE_FILE_TEST_CHILD", "1")
+        .status()
+}
+
+#[cfg(all(unix, not(all(target_os = "linux", feature = "unnamed-tmpfile"))))]
+fn is_child_process() -> bool {
+    std::env::var_os("ATOMIC_WRITE_FILE_TEST_CHILD").is_some()
+}
+
+#[test]
+#[cfg(all(unix, not(all(target_os = "linux", feature = "unnamed-tmpfile"))))]
+fn removes_tracked_temporary_files_at_exit() -> Result<()> {
+    let path = test_file("tracked-exit");
+    if is_child_process() {
+        crate::unix::remove_tracked_temporary_files_at_exit()?;
+        let _file = AtomicWriteFile::open(&path)?;
+        assert_eq!(list_temporary_files(&path).count(), 1);
+        std::process::exit(0);
+    }
+
+    let status = run_in_child_process("tests::removes_tracked_temporary_files_at_exit")?;
+    assert!(status.success(), "child process failed: {status}");
+    verify_no_leftovers(&path);
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, not(all(target_os = "linux", feature = "unnamed-tmpfile"))))]
+fn removes_tracked_temporary_files_on_signals() -> Result<()> {
+    use nix::sys::signal::Signal;
+    use nix::sys::signal::raise;
+    use std::os::unix::process::ExitStatusExt;
+
+    let path = test_file("tracked-signal");
+    if is_child_process() {
+        crate::unix::remove_tracked_temporary_files_on_signals(&[Signal::SIGTERM as i32])?;
+        let _file = AtomicWriteFile::open(&path)?;
+        assert_eq!(list_temporary_files(&path).count(), 1);
+        raise(Signal::SIGTERM)?;
+        unreachable!("process was not terminated by SIGTERM");
+    }
+
+    let status = run_in_child_process("tests::removes_tracked_temporary_files_on_signals")?;
+    assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+    verify_no_leftovers(&path);
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, feature = "cap-std"))]
+fn open_at_is_confined() -> Result<()> {
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+
+    let dir_path = test_subdirectory("open-at");
+    fs::create_dir(dir_path.join("sub"))?;
+    std::os::unix::fs::symlink("..", dir_path.join("sub").join("escape"))?;
+    let dir = Dir::open_ambient_dir(&dir_path, ambient_authority())?;
+
+    let mut file = OpenOptions::new().open_at(&dir, "sub/foo")?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(dir_path.join("sub").join("foo"))?, b"hello\n");
+
+    for path in [
+        "../open-at-escaped",
+        "/open-at-escaped",
+        "sub/escape/../open-at-escaped",
+        "sub/escape/escape/open-at-escaped",
+    ] {
+        assert!(
+            OpenOptions::new().open_at(&dir, path).is_err(),
+            "open_at({path:?}) should fail"
+        );
+    }
+    assert!(!test_directory().join("open-at-escaped").exists());
+
+    let file = OpenOptions::new()
+        .temporary_dir("../staging")
+        .open_at(&dir, "foo");
+    assert!(
+        file.is_err(),
+        "temporary directory outside of dir should be rejected"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, feature = "cap-std"))]
+fn open_at_confines_metadata_template() -> Result<()> {
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir_path = test_subdirectory("open-at-template");
+    fs::create_dir(dir_path.join("sub"))?;
+    fs::write(dir_path.join("template"), b"")?;
+    fs::set_permissions(dir_path.join("template"), fs::Permissions::from_mode(0o640))?;
+    let dir = Dir::open_ambient_dir(dir_path.join("sub"), ambient_authority())?;
+
+    // The template is outside of `dir`, whether it's reached through `..`, an absolute path, or
+    // a symlink
+    std::os::unix::fs::symlink("../template", dir_path.join("sub").join("escape"))?;
+    let absolute = fs::canonicalize(dir_path.join("template"))?;
+    for template in [Path::new("../template"), &absolute, Path::new("escape")] {
+        let err = OpenOptions::new()
+            .metadata_from(template)
+            .open_at(&dir, "foo")
+            .expect_err("open should fail");
+        assert_eq!(error_details(&err).stage(), Stage::CopyPermissions);
+    }
+
+    // Templates beneath `dir` are resolved relative to `dir`
+    fs::rename(
+        dir_path.join("template"),
+        dir_path.join("sub").join("template"),
+    )?;
+    fs::create_dir(dir_path.join("sub").join("nested"))?;
+    let file = OpenOptions::new()
+        .metadata_from("template")
+        .open_at(&dir, "nested/foo")?;
+    file.commit()?;
+    let mode = fs::metadata(dir_path.join("sub").join("nested").join("foo"))?
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o640);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, feature = "cap-std"))]
+fn open_at_checks_directory_beneath_dir() -> Result<()> {
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+
+    let dir_path = test_subdirectory("open-at-check-directory");
+    fs::create_dir(dir_path.join("open-at-sub"))?;
+    let dir = Dir::open_ambient_dir(&dir_path, ambient_authority())?;
+    let mut options = OpenOptions::new();
+    options.check_directory(true);
+
+    // `open-at-sub` does not exist in the current directory: it must not be looked up there
+    assert!(!Path::new("open-at-sub").exists());
+    let mut file = options.open_at(&dir, "open-at-sub/foo")?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(
+        fs::read(dir_path.join("open-at-sub").join("foo"))?,
+        b"hello\n"
+    );
+
+    // Moving the directory beneath `dir` is detected
+    let file = options.open_at(&dir, "open-at-sub/bar")?;
+    fs::rename(dir_path.join("open-at-sub"), dir_path.join("open-at-moved"))?;
+    let err = file
+        .try_commit()
+        .expect_err("moved directory should be detected");
+    assert_eq!(err.error.kind(), ErrorKind::NotFound);
+    assert_eq!(error_details(&err.error).stage(), Stage::CheckDirectory);
+
+    fs::rename(dir_path.join("open-at-moved"), dir_path.join("open-at-sub"))?;
+    err.file.commit()?;
+    assert!(dir_path.join("open-at-sub").join("bar").exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, feature = "cap-std"))]
+fn open_at_creates_parents() -> Result<()> {
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+
+    let dir_path = test_subdirectory("open-at-create-parents");
+    fs::create_dir(dir_path.join("sub"))?;
+    let dir = Dir::open_ambient_dir(dir_path.join("sub"), ambient_authority())?;
+
+    let err = OpenOptions::new()
+        .open_at(&dir, "a/b/foo")
+        .expect_err("parents should not be created");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    let mut file = OpenOptions::new()
+        .create_parents(true)
+        .open_at(&dir, "a/b/foo")?;
+    file.write_all(b"hello")?;
+    file.commit()?;
+    let path = dir_path.join("sub").join("a").join("b").join("foo");
+    assert_eq!(fs::read_to_string(&path)?, "hello");
+    verify_no_leftovers(&path);
+
+    // Directories are never created outside of `dir`
+    OpenOptions::new()
+        .create_parents(true)
+        .open_at(&dir, "../escaped/foo")
+        .expect_err("directories outside of dir should not be created");
+    assert!(!dir_path.join("escaped").exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, feature = "cap-std"))]
+fn open_at_in_place_fallback() -> Result<()> {
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir_path = test_subdirectory("open-at-in-place");
+    let dir = Dir::open_ambient_dir(&dir_path, ambient_authority())?;
+
+    // The contents can be read back, to be written in place if needed
+    let mut file = OpenOptions::new()
+        .in_place_fallback(true)
+        .open_at(&dir, "foo")?;
+    file.write_all(b"hello")?;
+    file.rewind()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    assert_eq!(contents, "hello");
+    file.commit()?;
+
+    // Privileged users can write to read-only directories
+    if nix::unistd::Uid::effective().is_root() {
+        return Ok(());
+    }
+
+    // The system temporary directory is outside of `dir`, and cannot be used as a fallback
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o555))?;
+    let result = OpenOptions::new()
+        .in_place_fallback(true)
+        .open_at(&dir, "foo");
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o755))?;
+    let err = result.expect_err("system temporary directory should not be used");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert_eq!(error_details(&err).stage(), Stage::CreateTemporaryFile);
+    verify_no_leftovers(std::env::temp_dir().join("foo"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store")]
+fn store_deduplicates_blobs() -> Result<()> {
+    use crate::store::Store;
+
+    let dir = test_subdirectory("store");
+    let store = Store::new(&dir);
+
+    let hash = store.insert(b"hello")?;
+    assert_eq!(
+        hash,
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+    assert!(store.contains(&hash)?);
+    assert_eq!(store.path(&hash)?, dir.join("2c").join(&hash));
+    assert_eq!(fs::read(store.path(&hash)?)?, b"hello");
+
+    // Inserting the same contents again (even concurrently) leaves the existing blob in place
+    let modified = |path: &Path| fs::metadata(path)?.modified();
+    let before = modified(&store.path(&hash)?)?;
+    let threads = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || store.insert(b"hello"))
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        assert_eq!(thread.join().expect("thread panicked")?, hash);
+    }
+    assert_eq!(modified(&store.path(&hash)?)?, before);
+
+    let mut writer = store.writer()?;
+    writer.write_all(b"discarded")?;
+    writer.discard()?;
+
+    assert_eq!(list_directory(&dir)?, ["2c"]);
+    assert_eq!(list_directory(dir.join("2c"))?, [hash.as_str()]);
+
+    let err = store.path("../foo").expect_err("invalid hash");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn computes_digest() -> Result<()> {
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    let path = test_file("digest");
+    let expected: [u8; 32] = Sha256::digest(b"hello world\n").into();
+
+    // Sequential writes
+    let mut file = OpenOptions::new().compute_digest(true).open(&path)?;
+    file.write_all(b"hello")?;
+    let name = "world";
+    write!(file, " {name}")?;
+    assert_eq!((&file).write_vectored(&[std::io::IoSlice::new(b"\n")])?, 1);
+    assert_eq!(file.digest()?, expected);
+
+    // Non-sequential writes fall back to reading the file back
+    let mut file = OpenOptions::new()
+        .compute_digest(true)
+        .read(true)
+        .open(&path)?;
+    file.write_all(b"hello earth\n")?;
+    file.seek(SeekFrom::Start(6))?;
+    file.write_all(b"world")?;
+    assert_eq!(file.stream_position()?, 11);
+    assert_eq!(file.digest()?, expected);
+    assert_eq!(file.stream_position()?, 11);
+
+    // Without `compute_digest()`, the file is always read back
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"hello world\n")?;
+    assert_eq!(file.digest()?, expected);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        let mut file = OpenOptions::new().compute_digest(true).open(&path)?;
+        file.write_all_at(b"hello ", 0)?;
+        file.write_all_at(b"world\n", 6)?;
+        assert_eq!(file.digest()?, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn commit_if_digest_discards_on_mismatch() -> Result<()> {
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    let path = test_file("commit-if-digest");
+    fs::write(&path, b"original\n")?;
+    let expected: [u8; 32] = Sha256::digest(b"hello\n").into();
+
+    let mut file = OpenOptions::new().compute_digest(true).open(&path)?;
+    file.write_all(b"corrupted\n")?;
+    let err = file
+        .commit_if_digest(&expected)
+        .expect_err("commit should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(error_details(&err).stage(), Stage::VerifyDigest);
+    assert_eq!(fs::read(&path)?, b"original\n");
+    verify_no_leftovers(&path);
+
+    let mut file = OpenOptions::new().compute_digest(true).open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.commit_if_digest(&expected)?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn records_digest_in_sidecar() -> Result<()> {
+    use crate::DigestRecord;
+
+    let path = test_file("record-digest-sidecar");
+    let sidecar_path = test_file("record-digest-sidecar.sha256");
+
+    let mut file = OpenOptions::new()
+        .compute_digest(true)
+        .record_digest(Some(DigestRecord::Sidecar))
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    assert!(!sidecar_path.exists());
+    file.commit()?;
+
+    assert_eq!(
+        fs::read_to_string(&sidecar_path)?,
+        "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  \
+         record-digest-sidecar\n"
+    );
+    assert!(DigestRecord::Sidecar.verify(&path)?);
+    verify_no_leftovers(&path);
+    verify_no_leftovers(&sidecar_path);
+
+    fs::write(&path, b"tampered\n")?;
+    assert!(!DigestRecord::Sidecar.verify(&path)?);
+
+    fs::write(&sidecar_path, b"not a digest\n")?;
+    let err = DigestRecord::Sidecar
+        .verify(&path)
+        .expect_err("verify should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn records_digest_in_xattr() -> Result<()> {
+    use crate::DigestRecord;
+
+    let path = test_file("record-digest-xattr");
+
+    let mut file = OpenOptions::new()
+        .compute_digest(true)
+        .record_digest(Some(DigestRecord::ExtendedAttribute))
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    match file.commit() {
+        Ok(()) => (),
+        Err(err) if matches!(err.kind(), ErrorKind::Unsupported) => {
+            // Extended attributes are not supported by the platform or by the filesystem
+            assert!(!path.exists());
+            verify_no_leftovers(&path);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    }
+
+    assert!(DigestRecord::ExtendedAttribute.verify(&path)?);
+    verify_no_leftovers(&path);
+
+    let mut file = fs::OpenOptions::new().append(true).open(&path)?;
+    file.write_all(b"tampered\n")?;
+    assert!(!DigestRecord::ExtendedAttribute.verify(&path)?);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn verify_without_recorded_digest() -> Result<()> {
+    use crate::DigestRecord;
+
+    let path = test_file("verify-no-digest");
+    let sidecar_path = test_file("verify-no-digest.sha256");
+    fs::write(&path, b"hello\n")?;
+    assert!(!sidecar_path.exists());
+
+    let err = DigestRecord::Sidecar
+        .verify(&path)
+        .expect_err("verify should fail");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    let err = DigestRecord::ExtendedAttribute
+        .verify(&path)
+        .expect_err("verify should fail");
+    match err.kind() {
+        ErrorKind::NotFound => (),
+        // Extended attributes are not supported by the platform or by the filesystem
+        ErrorKind::Unsupported => (),
+        _ => panic!("unexpected error: {err:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn commit_with_validation() -> Result<()> {
+    let path = test_file("commit-with-validation");
+    fs::write(&path, b"original\n")?;
+
+    let mut file = AtomicWriteFile::open(&path)?;
+    file.write_all(b"invalid\n")?;
+    let err = file
+        .commit_with_validation(|_file, temporary_path| {
+            assert_eq!(fs::read(temporary_path)?, b"invalid\n");
+            Err(std::io::Error::new(ErrorKind::InvalidData, "syntax error"))
+        })
+        .expect_err("commit should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(error_details(&err).stage(), Stage::Validate);
+    assert_eq!(fs::read(&path)?, b"original\n");
+    verify_no_leftovers(&path);
+
+    let mut file = AtomicWriteFile::open(&path)?;
+    file.write_all(b"valid\n")?;
+    file.commit_with_validation(|file, temporary_path| {
+        assert_eq!(fs::read(temporary_path)?, b"valid\n");
+        assert_eq!(file.metadata()?.len(), 6);
+        Ok(())
+    })?;
+    assert_eq!(fs::read(&path)?, b"valid\n");
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_text() -> Result<()> {
+    use crate::diff::Contents;
+
+    let path = test_file("dry-run-text");
+    let label = path.display();
+    fs::write(&path, b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n")?;
+
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"1\ntwo\n3\n4\n5\n6\n7\n9\n10\n11")?;
+    let diff = file.dry_run()?;
+    assert!(!diff.is_empty());
+    assert!(!diff.is_created());
+    assert_eq!(diff.metadata(), &[]);
+    assert_eq!(
+        diff.contents(),
+        &Contents::Text(format!(
+            "--- {label}\n+++ {label}\n\
+             @@ -1,10 +1,10 @@\n 1\n-2\n+two\n 3\n 4\n 5\n 6\n 7\n-8\n 9\n 10\n+11\n\
+             \\ No newline at end of file\n"
+        ))
+    );
+    assert_eq!(fs::read(&path)?, b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+    verify_no_leftovers(&path);
+
+    fs::write(&path, b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n")?;
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"new\na\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n")?;
+    assert_eq!(
+        file.dry_run()?.contents(),
+        &Contents::Text(format!(
+            "--- {label}\n+++ {label}\n\
+             @@ -1,3 +1,4 @@\n+new\n a\n b\n c\n\
+             @@ -10,4 +11,3 @@\n j\n k\n l\n-m\n"
+        ))
+    );
+    verify_no_leftovers(&path);
+
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n")?;
+    let diff = file.dry_run()?;
+    assert!(diff.is_empty());
+    assert_eq!(diff.contents(), &Contents::Unchanged);
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_large_text() -> Result<()> {
+    use crate::diff::Contents;
+
+    let path = test_file("dry-run-large-text");
+    let label = path.display();
+    let lines = |prefix: &str| {
+        (0..8000)
+            .map(|i| format!("{prefix} {i}\n"))
+            .collect::<String>()
+    };
+    fs::write(&path, format!("first\n{}last\n", lines("old")))?;
+
+    // Every line in the middle is different: instead of looking for the shortest edit script,
+    // which would take too much memory, the lines are shown as replaced
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    write!(file, "first\n{}last\n", lines("new"))?;
+    let deleted = lines("old").replace("old", "-old");
+    let inserted = lines("new").replace("new", "+new");
+    assert_eq!(
+        file.dry_run()?.contents(),
+        &Contents::Text(format!(
+            "--- {label}\n+++ {label}\n@@ -1,8002 +1,8002 @@\n first\n{deleted}{inserted} last\n"
+        ))
+    );
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_created() -> Result<()> {
+    use crate::diff::Contents;
+
+    let path = test_file("dry-run-created");
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"x\n")?;
+    let diff = file.dry_run()?;
+    assert!(diff.is_created());
+    assert_eq!(diff.metadata(), &[]);
+    assert_eq!(
+        diff.contents(),
+        &Contents::Text(format!(
+            "--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+x\n",
+            path.display()
+        ))
+    );
+    assert!(!path.exists());
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_binary() -> Result<()> {
+    use crate::diff::Contents;
+
+    let path = test_file("dry-run-binary");
+    fs::write(&path, b"\0\x01\x02\x03\x04\x05")?;
+
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"\0\xff\xff\x03\x04\x05\x06\x07")?;
+    assert_eq!(
+        file.dry_run()?.contents(),
+        &Contents::Binary(vec![1..3, 6..8])
+    );
+    assert_eq!(fs::read(&path)?, b"\0\x01\x02\x03\x04\x05");
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn dry_run_metadata() -> Result<()> {
+    use crate::diff::MetadataChange;
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = test_file("dry-run-metadata");
+    fs::write(&path, b"hello\n")?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .preserve_mode(false)
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o640))?;
+    let diff = file.dry_run()?;
+    assert!(!diff.is_empty());
+    assert_eq!(
+        diff.metadata(),
+        &[MetadataChange::Permissions {
+            old: fs::metadata(&path)?.permissions(),
+            new: fs::Permissions::from_mode(0o100640),
+        }]
+    );
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn dry_run_secret_mode_metadata() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = test_file("dry-run-secret-metadata");
+    fs::write(&path, b"hello\n")?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+
+    // The temporary file is private until it's committed, but the diff reports the permissions
+    // that it will be committed with
+    let mut file = OpenOptions::new()
+        .read(true)
+        .secret(true)
+        .permissions(fs::Permissions::from_mode(0o640))
+        .open(&path)?;
+    file.write_all(b"hello\n")?;
+    assert_eq!(file.metadata()?.permissions().mode() & 0o777, 0o600);
+    let diff = file.dry_run()?;
+    assert!(diff.is_empty());
+    assert_eq!(diff.metadata(), &[]);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn dry_run_non_regular_destination() -> Result<()> {
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use std::sync::mpsc;
+
+    let path = test_file("dry-run-fifo");
+    mkfifo(&path, Mode::from_bits_truncate(0o600))?;
+
+    // Run in a separate thread so that a regression makes the test fail instead of hanging
+    let (sender, receiver) = mpsc::channel();
+    let fifo_path = path.clone();
+    std::thread::spawn(move || {
+        let result = OpenOptions::new()
+            .read(true)
+            .open(&fifo_path)
+            .and_then(|mut file| {
+                file.write_all(b"hello\n")?;
+                file.dry_run()
+            });
+        sender.send(result).unwrap();
+    });
+    let err = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("dry run on a FIFO should not block")
+        .expect_err("dry run on a FIFO should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(error_details(&err).stage(), Stage::Diff);
+    assert!(path.exists());
+    verify_no_leftovers(&path);
+
+    let path = test_file("dry-run-symlink");
+    std::os::unix::fs::symlink("dry-run-symlink-target", &path)?;
+    fs::write(test_file("dry-run-symlink-target"), b"hello\n")?;
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    file.write_all(b"hello\n")?;
+    let err = file
+        .dry_run()
+        .expect_err("dry run on a symlink should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(error_details(&err).stage(), Stage::Diff);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn exact_mode_ignores_umask() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = test_file("exact-mode");
+    fs::write(&path, b"hello\n")?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    let file = OpenOptions::new().exact_mode(0o777).open(&path)?;
+    file.commit()?;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o777);
+
+    let file = OpenOptions::new()
+        .permissions(fs::Permissions::from_mode(0o640))
+        .open(&path)?;
+    file.commit()?;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o640);
+
+    Ok(())
+}
+
+#[test]
+fn sets_permissions() -> Result<()> {
+    let path = test_file("set-permissions");
+    let mut permissions = fs::metadata(test_directory())?.permissions();
+    permissions.set_readonly(true);
+
+    let mut file = OpenOptions::new().permissions(permissions).open(&path)?;
+    file.write_all(b"hello\n")?;
+    file.commit()?;
+    assert_eq!(fs::read(&path)?, b"hello\n");
+    assert!(fs::metadata(&path)?.permissions().readonly());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn sets_owner() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use crate::unix::user_id;
+    use nix::unistd::Gid;
+    use nix::unistd::Uid;
+    use std::os::unix::fs::MetadataExt;
+
+    let path = test_file("owner");
+    let uid = Uid::effective().as_raw();
+    let gid = Gid::effective().as_raw();
+
+    let file = OpenOptions::new().owner(Some(uid), Some(gid)).open(&path)?;
+    file.commit()?;
+    let metadata = fs::metadata(&path)?;
+    assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+
+    // Either the process is privileged and the owner is changed, or the error is ignored
+    let file = OpenOptions::new().try_owner(Some(0), None).open(&path)?;
+    file.commit()?;
+    let metadata = fs::metadata(&path)?;
+    assert!(metadata.uid() == 0 || metadata.uid() == uid);
+    assert_eq!(metadata.gid(), gid);
+
+    assert_eq!(user_id("root")?, 0);
+    assert_eq!(
+        user_id("no-such-user-atomic-write-file")
+            .expect_err("lookup should fail")
+            .kind(),
+        ErrorKind::NotFound
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn copies_metadata_from_template() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let template = test_file("template");
+    let path = test_file("from-template");
+    fs::write(&template, b"")?;
+    fs::set_permissions(&template, fs::Permissions::from_mode(0o640))?;
+    fs::write(&path, b"")?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    let file = OpenOptions::new().metadata_from(&template).open(&path)?;
+    file.commit()?;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o640);
+
+    let err = OpenOptions::new()
+        .metadata_from(test_file("missing-template"))
+        .open(&path)
+        .expect_err("open should fail");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(error_details(&err).stage(), Stage::CopyPermissions);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn secret_mode() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode =
+        |path: &Path| -> Result<u32> { Ok(fs::metadata(path)?.permissions().mode() & 0o7777) };
+
+    // The final mode is the same that the file would have without secret mode
+    let path = test_file("secret-default");
+    OpenOptions::new().open(&path)?.commit()?;
+    let default_mode = mode(&path)?;
+    fs::remove_file(&path)?;
+
+    let mut file = OpenOptions::new().secret(true).open(&path)?;
+    file.write_all(b"secret\n")?;
+    assert_eq!(
+        file.as_file().metadata()?.permissions().mode() & 0o7777,
+        0o600
+    );
+    file.commit()?;
+    assert_eq!(mode(&path)?, default_mode);
+    assert_eq!(fs::read(&path)?, b"secret\n");
+
+    let path = test_file("secret-exact");
+    let file = OpenOptions::new()
+        .secret(true)
+        .exact_mode(0o644)
+        .open(&path)?;
+    assert_eq!(
+        file.as_file().metadata()?.permissions().mode() & 0o7777,
+        0o600
+    );
+    file.commit()?;
+    assert_eq!(mode(&path)?, 0o644);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn secret_mode_overwrites_on_discard() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+
+    let path = test_file("secret-discard");
+    let mut file = OpenOptions::new().read(true).secret(true).open(&path)?;
+    file.write_all(b"secret\n")?;
+    let mut clone = file.as_file().try_clone()?;
+    file.discard()?;
+
+    let mut contents = Vec::new();
+    clone.seek(SeekFrom::Start(0))?;
+    clone.read_to_end(&mut contents)?;
+    assert_eq!(contents, [0; 7]);
+    assert!(!path.exists());
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn secret_mode_keeps_destination_after_failed_commit() -> Result<()> {
+    use crate::imp::FAIL_SYNC_DIR;
+    use crate::unix::OpenOptionsExt;
+
+    let path = test_file("secret-failed-commit");
+    let mut file = OpenOptions::new().secret(true).open(&path)?;
+    file.write_all(b"secret\n")?;
+    FAIL_SYNC_DIR.set(Some(0));
+    let err = file.try_commit().expect_err("commit should fail");
+    assert!(error_details(&err.error).is_destination_replaced());
+
+    // After the rename, the file refers to the destination, which must not be overwritten or
+    // removed, even if the temporary file is explicitly removed
+    let mut file = err.file;
+    file.temporary_file.remove_file()?;
+    file.discard()?;
+    assert_eq!(fs::read(&path)?, b"secret\n");
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+fn check_destination() -> Result<()> {
+    let dir_path = test_subdirectory("check-destination");
+    fs::create_dir(dir_path.join("dir"))?;
+    fs::write(dir_path.join("file"), "hello")?;
+    let mut options = OpenOptions::new();
+    options.check_destination(true);
+
+    let err = options
+        .open(dir_path.join("dir"))
+        .expect_err("directory should be rejected");
+    assert_eq!(err.kind(), ErrorKind::IsADirectory);
+    assert_eq!(error_details(&err).stage(), Stage::CheckDestination);
+    assert_eq!(fs::read_dir(dir_path.join("dir"))?.count(), 0);
+
+    let file = options.open(dir_path.join("file"))?;
+    file.commit()?;
+    let file = options.open(dir_path.join("new"))?;
+    file.commit()?;
+    assert!(dir_path.join("new").exists());
+
+    // Without the option, directories are only rejected at commit time
+    let file = AtomicWriteFile::open(dir_path.join("dir"))?;
+    file.commit().expect_err("directory should not be replaced");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn check_destination_rejects_read_only_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Privileged users can write to read-only directories
+    if nix::unistd::Uid::effective().is_root() {
+        return Ok(());
+    }
+
+    let dir_path = test_subdirectory("check-destination-read-only");
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o555))?;
+    let result = OpenOptions::new()
+        .check_destination(true)
+        .open(dir_path.join("foo"));
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o755))?;
+
+    let err = result.expect_err("read-only directory should be rejected");
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(error_details(&err).stage(), Stage::CheckDestination);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn in_place_fallback_read_only_directory() -> Result<()> {
+    use crate::CommitOutcome;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Privileged users can write to read-only directories
+    if nix::unistd::Uid::effective().is_root() {
+        return Ok(());
+    }
+
+    let dir_path = test_subdirectory("in-place-read-only");
+    let path = dir_path.join("foo");
+    fs::write(&path, "old contents, longer than the new ones")?;
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o555))?;
+
+    let result = (|| {
+        AtomicWriteFile::open(&path).expect_err("directory should not be writable");
+
+        let mut file = OpenOptions::new().in_place_fallback(true).open(&path)?;
+        assert!(file.is_in_place());
+        file.write_all(b"new contents")?;
+        file.commit_with_outcome()
+    })();
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o755))?;
+
+    assert_eq!(result?, CommitOutcome::InPlace);
+    assert_eq!(fs::read_to_string(&path)?, "new contents");
+    verify_no_leftovers(&path);
+    verify_no_leftovers(std::env::temp_dir().join("foo"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+#[cfg(target_os = "linux")]
+fn check_destination_rejects_bind_mount() -> Result<()> {
+    use std::process::Command;
+    use std::process::Stdio;
+
+    // The source of the bind mount is on the same filesystem as the destination, so both have the
+    // same device ID
+    let dir_path = test_subdirectory("check-destination-bind-mount");
+    let path = dir_path.join("foo");
+    let source_path = dir_path.join("source");
+    fs::write(&path, "")?;
+    fs::write(&source_path, "")?;
+
+    // Bind-mounting requires privileges, which may not be available
+    let mounted = Command::new("mount")
+        .arg("--bind")
+        .arg(&source_path)
+        .arg(&path)
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !mounted {
+        return Ok(());
+    }
+
+    let result = OpenOptions::new().check_destination(true).open(&path);
+    let _ = Command::new("umount").arg(&path).status();
+
+    let err = result.expect_err("bind mount should be rejected");
+    assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+    assert_eq!(error_details(&err).stage(), Stage::CheckDestination);
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+#[cfg(target_os = "linux")]
+fn in_place_fallback_busy_destination() -> Result<()> {
+    use crate::CommitOutcome;
+    use crate::unix::OpenOptionsExt;
+    use std::process::Command;
+    use std::process::Stdio;
+
+    let dir_path = test_subdirectory("in-place-busy");
+    let path = dir_path.join("foo");
+    let source_path = dir_path.join("source");
+    fs::write(&path, "old contents, longer than the new ones")?;
+    fs::write(&source_path, "bind-mounted contents")?;
+
+    // Bind-mounting requires privileges, which may not be available
+    let mounted = Command::new("mount")
+        .arg("--bind")
+        .arg(&source_path)
+        .arg(&path)
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !mounted {
+        return Ok(());
+    }
+
+    let result = (|| {
+        let file = AtomicWriteFile::open(&path)?;
+        writeln!(&file, "atomic")?;
+        let err = file
+            .commit()
+            .expect_err("bind mount should not be replaced");
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+
+        let mut file = OpenOptions::new()
+            .in_place_fallback(true)
+            .lock_in_place(true)
+            .open(&path)?;
+        assert!(!file.is_in_place());
+        file.write_all(b"new contents")?;
+        file.commit_with_outcome()
+    })();
+    let contents = fs::read_to_string(&path);
+    let _ = Command::new("umount").arg(&path).status();
+
+    assert_eq!(result?, CommitOutcome::InPlace);
+    assert_eq!(contents?, "new contents");
+    assert_eq!(fs::read_to_string(&source_path)?, "new contents");
+
+    Ok(())
+}
+
+#[test]
+fn creates_parents() -> Result<()> {
+    let dir_path = test_subdirectory("create-parents");
+    let path = dir_path.join("a").join("b").join("foo");
+
+    let err = AtomicWriteFile::open(&path).expect_err("parents should not be created");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    let mut file = OpenOptions::new().create_parents(true).open(&path)?;
+    file.write_all(b"hello")?;
+    file.commit()?;
+    assert_eq!(fs::read_to_string(&path)?, "hello");
+
+    // Existing directories are fine
+    let file = OpenOptions::new().create_parents(true).open(&path)?;
+    file.commit()?;
+    verify_no_leftovers(&path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn creates_parents_with_mode() -> Result<()> {
+    use crate::unix::OpenOptionsExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir_path = test_subdirectory("create-parents-mode");
+    let file = OpenOptions::new()
+        .create_parents(true)
+        .parents_mode(0o700)
+        .open(dir_path.join("a").join("b").join("foo"))?;
+    file.commit()?;
+
+    for dir in [dir_path.join("a"), dir_path.join("a").join("b")] {
+        assert_eq!(fs::metadata(dir)?.permissions().mode() & 0o7777, 0o700);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn check_directory_detects_moved_directory() -> Result<()> {
+    let dir_path = test_subdirectory("check-directory");
+    let original = dir_path.join("original");
+    let moved = dir_path.join("moved");
+    fs::create_dir(&original)?;
+    let mut options = OpenOptions::new();
+    options.check_directory(true);
+
+    // Moved away
+    let file = options.open(original.join("foo"))?;
+    writeln!(&file, "hello")?;
+    fs::rename(&original, &moved)?;
+    let err = file
+        .try_commit()
+        .expect_err("moved directory should be detected");
+    assert_eq!(err.error.kind(), ErrorKind::NotFound);
+    assert_eq!(error_details(&err.error).stage(), Stage::CheckDirectory);
+
+    // Replaced by another directory
+    fs::create_dir(&original)?;
+    let file = err.file;
+    let err = file
+        .try_commit()
+        .expect_err("replaced directory should be detected");
+    assert_eq!(error_details(&err.error).stage(), Stage::CheckDirectory);
+
+    // Moved back: the commit can be retried
+    fs::remove_dir(&original)?;
+    fs::rename(&moved, &original)?;
+    err.file.commit()?;
+    assert_eq!(fs::read_to_string(original.join("foo"))?, "hello\n");
+    verify_no_leftovers(original.join("foo"));
+
+    // Without the check, the file is committed into the moved directory
+    let file = AtomicWriteFile::open(original.join("bar"))?;
+    fs::rename(&original, &moved)?;
+    file.commit()?;
+    assert!(moved.join("bar").exists());
+
+    // If the commit fails, the file is discarded
+    let file = options.open(moved.join("baz"))?;
+    fs::rename(&moved, &original)?;
+    file.commit()
+        .expect_err("moved directory should be detected");
+    assert!(!original.join("baz").exists());
+    verify_no_leftovers(original.join("baz"));
+
+    Ok(())
+}
@@ -3,11 +3,18 @@
 use crate::AtomicWriteFile;
 use crate::OpenOptions;
 use crate::imp::Preserve;
+use crate::imp::SetOwner;
+use crate::imp::registry;
 use nix::sys::stat::mode_t;
+use nix::unistd::Gid;
+use nix::unistd::Group;
+use nix::unistd::Uid;
+use nix::unistd::User;
+use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Result;
 use std::os::unix::fs;
 
-#[cfg(feature = "unstable-unix_file_vectored_at")]
 use std::io::IoSlice;
 
 #[cfg(feature = "unstable-unix_file_vectored_at")]
@@ -73,6 +80,79 @@ pub trait OpenOptionsExt {
     /// ```
     fn preserve_mode(&mut self, preserve_mode: bool) -> &mut Self;
 
+    /// Sets the exact mode of the atomically-written file, including the setuid, setgid and sticky
+    /// bits.
+    ///
+    /// Unlike [`std::os::unix::fs::OpenOptionsExt::mode()`], which is passed to `open(2)` and is
+    /// therefore masked with the process umask, the mode set with this method is applied with
+    /// `fchmod(2)` when [`OpenOptions::open()`] is called, and is not affected by the umask. It
+    /// takes precedence over [`preserve_mode()`](OpenOptionsExt::preserve_mode), and is applied
+    /// after the owner is preserved (see [`preserve_owner()`](OpenOptionsExt::preserve_owner)),
+    /// so that the setuid and setgid bits are not cleared by changing the owner.
+    ///
+    /// This is equivalent to calling [`OpenOptions::permissions()`] with
+    /// [`Permissions::from_mode(mode)`](std::os::unix::fs::PermissionsExt::from_mode).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.exact_mode(0o2775);
+    /// let file = options.open("foo.txt")?;
+    /// file.commit()?; // "foo.txt" is saved with mode 2775, regardless of the umask
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn exact_mode(&mut self, mode: u32) -> &mut Self;
+
+    /// Keeps the contents of the atomically-written file private until it's committed.
+    ///
+    /// By default, the temporary file is created with its final mode (see
+    /// [`preserve_mode()`](OpenOptionsExt::preserve_mode)), so other users may be able to open it
+    /// and read partially written contents before it's committed. If `true`:
+    ///
+    /// * The temporary file is created with mode `0o600`, so that only its owner can access it.
+    ///   (With the [`unnamed-tmpfile` feature](crate#unnamed-tmpfile-linux-only), the temporary
+    ///   file has no name, but it may still be opened through `/proc`.)
+    ///
+    /// * The final mode (preserved from the original file, set with
+    ///   [`exact_mode()`](OpenOptionsExt::exact_mode), or the default mode masked with the process
+    ///   umask) is only set when the file is committed, right before it's renamed. On Linux, the
+    ///   umask is read from `/proc/self/status`; if it cannot be determined, the default mode is
+    ///   `0o600`.
+    ///
+    /// * When the file is discarded (or dropped without being committed), its contents are
+    ///   overwritten with zeros before it's removed. This is best-effort: on copy-on-write
+    ///   filesystems, and on storage devices that remap blocks, the contents may survive.
+    ///
+    /// The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    /// use std::io::Write;
+    ///
+    /// let mut file = OpenOptions::new().secret(true).open("secret.key")?;
+    /// file.write_all(b"hunter2")?; // other users cannot read this yet
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn secret(&mut self, secret: bool) -> &mut Self;
+
     /// Specifies whether the atomically-written file should have the same ownership (user/group)
     /// of the original file (if any).
     ///
@@ -161,6 +241,260 @@ pub trait OpenOptionsExt {
     /// # }
     /// ```
     fn try_preserve_owner(&mut self, try_preserve_owner: bool) -> &mut Self;
+
+    /// Sets the owner (user and group) of the atomically-written file, regardless of the owner of
+    /// the original file (if any).
+    ///
+    /// The owner is set with `fchown(2)` when [`OpenOptions::open()`] is called, and takes
+    /// precedence over [`preserve_owner()`](OpenOptionsExt::preserve_owner). If `uid` or `gid` is
+    /// `None`, the user or group is left unchanged (or copied from the original file, if the owner
+    /// is preserved). User and group names can be converted to IDs with [`user_id()`] and
+    /// [`group_id()`].
+    ///
+    /// Like [`preserve_owner()`](OpenOptionsExt::preserve_owner), this causes
+    /// [`OpenOptions::open()`] to fail if the owner cannot be set. Use
+    /// [`try_owner()`](OpenOptionsExt::try_owner) to ignore errors when the process does not have
+    /// enough privileges.
+    ///
+    /// Calling `owner()` overrides any previous call to `owner()` or `try_owner()`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    /// use atomic_write_file::unix::group_id;
+    /// use atomic_write_file::unix::user_id;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.owner(Some(user_id("www-data")?), Some(group_id("www-data")?));
+    /// let file = options.open("/var/www/index.html")?;
+    /// file.commit()?; // "index.html" is saved with owner "www-data:www-data"
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut Self;
+
+    /// Sets the owner (user and group) of the atomically-written file, ignoring errors if the
+    /// process does not have enough privileges.
+    ///
+    /// This is equivalent to [`owner()`](OpenOptionsExt::owner), with the exception that
+    /// [`OpenOptions::open()`] does not fail with a "Operation not permitted" (`EPERM`) error if
+    /// the process is not privileged, in the same way as
+    /// [`try_preserve_owner()`](OpenOptionsExt::try_preserve_owner).
+    ///
+    /// Calling `try_owner()` overrides any previous call to `owner()` or `try_owner()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.try_owner(Some(0), Some(0));
+    /// let file = options.open("foo.txt")?; // this won't fail if the process is not privileged
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn try_owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut Self;
+
+    /// Specifies whether the names of temporary files should embed the ID of the process that
+    /// created them.
+    ///
+    /// If `true`, temporary files are named `.filename.XXXXXX.PID-TIME`, where `PID` is the ID of
+    /// the current process, and `TIME` is the number of seconds elapsed since boot when the
+    /// temporary file was created. This allows [`CleanupOptions`](crate::CleanupOptions) to tell
+    /// temporary files that are still being written apart from temporary files that were abandoned
+    /// by a process that was interrupted abruptly: temporary files whose process is still running
+    /// are never removed, regardless of their age. On Linux, the start time of the process is
+    /// also checked, so that temporary files are not mistaken for in-progress ones if their process
+    /// ID has been reused.
+    ///
+    /// If `false` (the default), temporary files are named `.filename.XXXXXX`, where `XXXXXX` is
+    /// a random suffix.
+    ///
+    /// **Note:** process IDs are only meaningful on the machine, and in the PID namespace, where
+    /// they were assigned. If the same directory is shared between multiple machines (for example,
+    /// over NFS), or between processes in different PID namespaces (for example, containers that
+    /// share a volume), a temporary file that is still being written may belong to a process that
+    /// cannot be seen. Such temporary files are treated like temporary files without a process ID:
+    /// they are only removed if they are older than
+    /// [`CleanupOptions::max_age()`](crate::CleanupOptions::max_age), which must then be longer
+    /// than the time it takes to write a file. This option also has no effect on anonymous
+    /// temporary files (see the [`unnamed-tmpfile` feature](crate#unnamed-tmpfile-linux-only)).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::CleanupOptions;
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    /// use std::time::Duration;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.pid_in_temporary_name(true);
+    /// let file = options.open("foo.txt")?;
+    ///
+    /// // The temporary file of `file` is not removed, because this process is still running
+    /// CleanupOptions::new()
+    ///     .max_age(Duration::ZERO)
+    ///     .clean_file("foo.txt")?;
+    ///
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn pid_in_temporary_name(&mut self, pid_in_temporary_name: bool) -> &mut Self;
+
+    /// Share directory file descriptors between [`AtomicWriteFile`]s in the same directory.
+    ///
+    /// By default, every [`AtomicWriteFile`] opens its own file descriptor for the directory that
+    /// contains it, which stays open until the [`AtomicWriteFile`] is dropped. With many
+    /// [`AtomicWriteFile`]s open at the same time, this may exhaust the file descriptor limit of
+    /// the process.
+    ///
+    /// If `true`, directory file descriptors are cached process-wide and shared between all the
+    /// [`AtomicWriteFile`]s opened with this option in the same directory. The parent directory
+    /// is then looked up with `stat(2)` instead of `open(2)`. Cached descriptors are identified by
+    /// device and inode number, so if the directory is renamed or replaced by another one, a new
+    /// file descriptor is opened for the new directory. Directory file descriptors are closed as
+    /// soon as the last [`AtomicWriteFile`] using them is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.cache_directories(true);
+    ///
+    /// // Both files share the same directory file descriptor
+    /// let foo = options.open("foo.txt")?;
+    /// let bar = options.open("bar.txt")?;
+    /// foo.commit()?;
+    /// bar.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn cache_directories(&mut self, cache_directories: bool) -> &mut Self;
+
+    /// Resolve directories without following symlinks, and refuse to operate in sticky
+    /// directories owned by other users.
+    ///
+    /// By default, the directory containing the destination file is opened with `open(2)`, which
+    /// follows symlinks in any component of its path. If other users can write to any of the
+    /// directories along the path, they may replace a directory with a symlink between the time
+    /// the path is checked and the time the file is written, redirecting the write to a location
+    /// of their choice.
+    ///
+    /// If `true`:
+    ///
+    /// * The directory containing the destination file (and the directory set with
+    ///   [`OpenOptions::temporary_dir()`], if any) is opened without following any symlinks. On
+    ///   Linux, this is done with `openat2(2)` and `RESOLVE_NO_SYMLINKS`; on other platforms, or
+    ///   on kernels that do not support `openat2(2)`, each component of the path is opened in turn
+    ///   with `O_NOFOLLOW`. If the path contains a symlink, opening fails with an error (`ELOOP`).
+    ///
+    /// * Opening fails with an error of kind
+    ///   [`PermissionDenied`](std::io::ErrorKind::PermissionDenied) if the directory has the
+    ///   sticky bit set and is not owned by the effective user of the process (like `/tmp`). In
+    ///   such directories, other users can create files that cannot be removed or replaced.
+    ///
+    /// The destination file itself is never followed if it is a symlink: see the [module-level
+    /// documentation](crate#notes-and-limitations).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let file = OpenOptions::new()
+    ///     .secure_resolution(true)
+    ///     .open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn secure_resolution(&mut self, secure_resolution: bool) -> &mut Self;
+
+    /// Specifies whether the destination file should be locked while it's written in place.
+    ///
+    /// When the destination file cannot be replaced, and
+    /// [`OpenOptions::in_place_fallback()`](crate::OpenOptions::in_place_fallback) is enabled, the
+    /// contents are written directly to the destination file. If `true`, an exclusive `flock(2)`
+    /// lock is acquired on the destination file before writing to it, and released afterwards,
+    /// waiting for other processes that hold a lock on it. This only protects against processes
+    /// that lock the file in the same way: readers that do not lock the file may still see
+    /// partially written contents.
+    ///
+    /// The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let file = OpenOptions::new()
+    ///     .in_place_fallback(true)
+    ///     .lock_in_place(true)
+    ///     .open("foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn lock_in_place(&mut self, lock_in_place: bool) -> &mut Self;
+
+    /// Sets the mode of the directories created by
+    /// [`OpenOptions::create_parents()`](crate::OpenOptions::create_parents).
+    ///
+    /// Like [`std::os::unix::fs::DirBuilderExt::mode()`], the mode is masked by the process
+    /// umask. The default is `0o777`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+    /// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+    /// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+    /// use atomic_write_file::OpenOptions;
+    /// use atomic_write_file::unix::OpenOptionsExt;
+    ///
+    /// let file = OpenOptions::new()
+    ///     .create_parents(true)
+    ///     .parents_mode(0o700)
+    ///     .open("private/directories/foo.txt")?;
+    /// file.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn parents_mode(&mut self, mode: u32) -> &mut Self;
 }
 
 impl OpenOptionsExt for OpenOptions {
@@ -170,6 +504,12 @@ impl OpenOptionsExt for OpenOptions {
         self
     }
 
+    #[inline]
+    fn exact_mode(&mut self, mode: u32) -> &mut Self {
+        self.inner.exact_mode = Some(mode);
+        self
+    }
+
     #[inline]
     fn preserve_owner(&mut self, preserve_owner: bool) -> &mut Self {
         self.inner.preserve_owner = match preserve_owner {
@@ -187,6 +527,203 @@ impl OpenOptionsExt for OpenOptions {
         };
         self
     }
+
+    #[inline]
+    fn secret(&mut self, secret: bool) -> &mut Self {
+        self.inner.secret = secret;
+        self
+    }
+
+    #[inline]
+    fn owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut Self {
+        self.inner.owner = Some(SetOwner {
+            uid: uid.map(Uid::from_raw),
+            gid: gid.map(Gid::from_raw),
+            policy: Preserve::Yes,
+        });
+        self
+    }
+
+    #[inline]
+    fn try_owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut Self {
+        self.inner.owner = Some(SetOwner {
+            uid: uid.map(Uid::from_raw),
+            gid: gid.map(Gid::from_raw),
+            policy: Preserve::Try,
+        });
+        self
+    }
+
+    #[inline]
+    fn pid_in_temporary_name(&mut self, pid_in_temporary_name: bool) -> &mut Self {
+        self.inner.pid_in_name = pid_in_temporary_name;
+        self
+    }
+
+    #[inline]
+    fn cache_directories(&mut self, cache_directories: bool) -> &mut Self {
+        self.inner.cache_dirs = cache_directories;
+        self
+    }
+
+    #[inline]
+    fn secure_resolution(&mut self, secure_resolution: bool) -> &mut Self {
+        self.inner.secure_resolution = secure_resolution;
+        self
+    }
+
+    #[inline]
+    fn lock_in_place(&mut self, lock_in_place: bool) -> &mut Self {
+        self.inner.lock_in_place = lock_in_place;
+        self
+    }
+
+    #[inline]
+    fn parents_mode(&mut self, mode: u32) -> &mut Self {
+        self.inner.parents_mode = mode as mode_t;
+        self
+    }
+}
+
+/// Returns the ID of the user named `name`, for use with [`OpenOptionsExt::owner()`].
+///
+/// Returns an error of kind [`NotFound`](std::io::ErrorKind::NotFound) if no such user exists.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// use atomic_write_file::unix::user_id;
+///
+/// assert_eq!(user_id("root")?, 0);
+/// # Ok(())
+/// # }
+/// ```
+pub fn user_id(name: &str) -> Result<u32> {
+    match User::from_name(name)? {
+        Some(user) => Ok(user.uid.as_raw()),
+        None => Err(Error::new(ErrorKind::NotFound, "user not found")),
+    }
+}
+
+/// Returns the ID of the group named `name`, for use with [`OpenOptionsExt::owner()`].
+///
+/// Returns an error of kind [`NotFound`](std::io::ErrorKind::NotFound) if no such group exists.
+pub fn group_id(name: &str) -> Result<u32> {
+    match Group::from_name(name)? {
+        Some(group) => Ok(group.gid.as_raw()),
+        None => Err(Error::new(ErrorKind::NotFound, "group not found")),
+    }
+}
+
+/// Starts keeping track of the temporary files of all the [`AtomicWriteFile`]s opened from now on,
+/// so that they can be removed with [`remove_tracked_temporary_files()`].
+///
+/// [`AtomicWriteFile`] removes its temporary file when it is committed, discarded, or dropped.
+/// However, destructors are not run if the process exits via [`std::process::exit()`], or if it
+/// is terminated by a signal like `SIGTERM` or `SIGINT`. In those cases, temporary files are left
+/// behind. Tracking temporary files allows removing them in those situations too.
+///
+/// Tracking is process-wide and cannot be disabled once enabled. Temporary files of
+/// [`AtomicWriteFile`]s that were opened before calling this function are not tracked.
+///
+/// This function is called automatically by [`remove_tracked_temporary_files_at_exit()`] and
+/// [`remove_tracked_temporary_files_on_signals()`]. It only needs to be called explicitly when
+/// calling [`remove_tracked_temporary_files()`] from a custom signal handler.
+///
+/// **Note:** anonymous temporary files (see the [`unnamed-tmpfile`
+/// feature](crate#unnamed-tmpfile-linux-only)) are removed automatically by the operating system
+/// and are never tracked.
+pub fn track_temporary_files() {
+    registry::enable();
+}
+
+/// Removes the temporary files of all the [`AtomicWriteFile`]s that are currently open, if
+/// tracking was enabled with [`track_temporary_files()`].
+///
+/// This function is async-signal-safe: it does not allocate memory nor take locks, and can be
+/// safely called from a signal handler. Errors are ignored.
+///
+/// After calling this function, open [`AtomicWriteFile`]s can no longer be committed, so this
+/// function should only be called when the process is about to terminate.
+///
+/// # Examples
+///
+/// ```no_run
+/// use atomic_write_file::unix::remove_tracked_temporary_files;
+/// use atomic_write_file::unix::track_temporary_files;
+///
+/// extern "C" fn handle_signal(_signal: i32) {
+///     remove_tracked_temporary_files();
+///     // ...
+/// }
+///
+/// track_temporary_files();
+/// // Install `handle_signal` using `sigaction(2)`, or any other crate of your choice
+/// # let _ = handle_signal;
+/// ```
+pub fn remove_tracked_temporary_files() {
+    registry::remove_all();
+}
+
+/// Enables tracking of temporary files (see [`track_temporary_files()`]), and arranges for them to
+/// be removed when the process exits via [`std::process::exit()`] or by returning from `main`.
+///
+/// The removal is performed by a function registered with `atexit(3)`. Calling this function more
+/// than once has no additional effect.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let test_dir = option_env!("TEST_DIR").unwrap_or("target/test-files");
+/// # std::fs::create_dir_all(&test_dir).expect("failed to create test dir");
+/// # std::env::set_current_dir(test_dir).expect("failed to move to test dir");
+/// use atomic_write_file::AtomicWriteFile;
+/// use atomic_write_file::unix::remove_tracked_temporary_files_at_exit;
+///
+/// remove_tracked_temporary_files_at_exit()?;
+///
+/// let file = AtomicWriteFile::open("foo.txt")?;
+/// // If `std::process::exit()` is called here, the temporary file of `file` is removed
+/// file.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn remove_tracked_temporary_files_at_exit() -> Result<()> {
+    registry::remove_all_at_exit_hook()?;
+    Ok(())
+}
+
+/// Enables tracking of temporary files (see [`track_temporary_files()`]), and installs signal
+/// handlers for `signals` that remove them.
+///
+/// After removing the temporary files, the signal handler restores the default action for the
+/// signal and raises it again, so that the process terminates as if the handler was never
+/// installed. Any handler previously installed for `signals` is replaced. If you need custom
+/// signal handling, call [`remove_tracked_temporary_files()`] from your own handler instead.
+///
+/// Returns an error of kind [`InvalidInput`](std::io::ErrorKind::InvalidInput) if any of
+/// `signals` is not a valid signal number, or if it is a signal that cannot be caught (like
+/// `SIGKILL`).
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// use atomic_write_file::unix::remove_tracked_temporary_files_on_signals;
+///
+/// const SIGHUP: i32 = 1;
+/// const SIGINT: i32 = 2;
+/// const SIGTERM: i32 = 15;
+///
+/// remove_tracked_temporary_files_on_signals(&[SIGHUP, SIGINT, SIGTERM])?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn remove_tracked_temporary_files_on_signals(signals: &[i32]) -> Result<()> {
+    registry::remove_all_on_signals(signals)?;
+    Ok(())
 }
 
 impl fs::FileExt for AtomicWriteFile {
@@ -196,7 +733,9 @@ impl fs::FileExt for AtomicWriteFile {
     }
     #[inline]
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        self.temporary_file.file.write_at(buf, offset)
+        self.tracked_write(Some(offset), &[IoSlice::new(buf)], |file| {
+            file.write_at(buf, offset)
+        })
     }
 
     #[inline]
@@ -213,11 +752,16 @@ impl fs::FileExt for AtomicWriteFile {
     #[inline]
     #[cfg(feature = "unstable-unix_file_vectored_at")]
     fn write_vectored_at(&self, bufs: &[IoSlice<'_>], offset: u64) -> Result<usize> {
-        self.temporary_file.file.write_vectored_at(bufs, offset)
+        self.tracked_write(Some(offset), bufs, |file| {
+            file.write_vectored_at(bufs, offset)
+        })
     }
 
     #[inline]
     fn write_all_at(&self, buf: &[u8], offset: u64) -> Result<()> {
-        self.temporary_file.file.write_all_at(buf, offset)
+        self.tracked_write(Some(offset), &[IoSlice::new(buf)], |file| {
+            file.write_all_at(buf, offset).map(|()| buf.len())
+        })?;
+        Ok(())
     }
 }